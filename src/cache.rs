@@ -0,0 +1,530 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+
+use reqwest::{
+    header::{HeaderMap, ETAG, LAST_MODIFIED, SET_COOKIE},
+    StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::AsyncWriteExt,
+};
+use tracing::warn;
+
+use crate::{
+    codec::{Codec, Compression},
+    requester::{header_pairs, pairs_to_headers},
+};
+
+/// Suffix of files that are still being written.
+pub const TMP_SUFFIX: &str = ".tmp";
+
+#[derive(Clone, Debug)]
+pub struct CachePolicy {
+    /// entries older than this are stale, None keeps entries fresh forever
+    pub max_age: Option<Duration>,
+    /// refresh stale entries with a conditional request instead of a full download
+    pub revalidate: bool,
+    pub eviction: EvictionPolicy,
+    /// used for new entries, existing ones keep the codec they were written with
+    pub compression: Compression,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy {
+            max_age: None,
+            revalidate: true,
+            eviction: EvictionPolicy::default(),
+            compression: Compression::default(),
+        }
+    }
+}
+
+impl CachePolicy {
+    pub fn is_fresh(&self, metadata: &CacheMetadata) -> bool {
+        self.max_age
+            .map_or(true, |max_age| metadata.age() <= max_age)
+    }
+}
+
+/// Applied when a crawl starts and by `cache evict`.
+#[derive(Clone, Debug, Default)]
+pub struct EvictionPolicy {
+    /// total size of the stored bodies
+    pub max_bytes: Option<u64>,
+    /// entries fetched longer ago than this are deleted
+    pub max_age: Option<Duration>,
+    pub order: EvictionOrder,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvictionOrder {
+    /// least recently read or written first
+    #[default]
+    Lru,
+    OldestFirst,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    pub url: Url,
+    /// seconds since the unix epoch
+    pub fetched_at: u64,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    #[serde(default)]
+    pub codec: Codec,
+}
+
+impl CacheMetadata {
+    pub fn new(url: &Url, status: StatusCode, headers: &HeaderMap) -> CacheMetadata {
+        let mut headers = headers.clone();
+        headers.remove(SET_COOKIE);
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        CacheMetadata {
+            url: url.clone(),
+            fetched_at: now(),
+            status: status.as_u16(),
+            etag: value(ETAG),
+            last_modified: value(LAST_MODIFIED),
+            headers: header_pairs(&headers),
+            codec: Codec::default(),
+        }
+    }
+
+    /// Marks the entry as fetched now after a 304, taking over any updated validators.
+    pub fn refresh(&mut self, headers: &HeaderMap) {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        self.fetched_at = now();
+        self.etag = value(ETAG).or(self.etag.take());
+        self.last_modified = value(LAST_MODIFIED).or(self.last_modified.take());
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK)
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        pairs_to_headers(self.headers.clone()).unwrap_or_default()
+    }
+
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.fetched_at))
+    }
+
+    pub fn can_revalidate(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so a crash
+/// leaves either the old or the complete new file behind.
+pub async fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap()).await?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{:08x}{TMP_SUFFIX}", rand::random::<u32>()));
+    let tmp = PathBuf::from(tmp);
+    let written = async {
+        let mut file = File::create(&tmp).await?;
+        file.write_all(content).await?;
+        file.sync_all().await
+    }
+    .await;
+    match written {
+        Ok(()) => fs::rename(&tmp, path).await,
+        Err(e) => {
+            let _ = fs::remove_file(&tmp).await;
+            Err(e)
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The key of a plain GET of `url`, see `Fingerprinter`.
+pub fn key(url: &Url) -> String {
+    hex::encode(Sha256::digest(url.as_str().as_bytes()))
+}
+
+pub struct CachedPage {
+    pub content: Vec<u8>,
+    pub metadata: CacheMetadata,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub url: Url,
+    pub key: String,
+    /// sha256 of the stored body
+    pub object: String,
+    pub fetched_at: u64,
+    pub size: u64,
+    /// last read or write, reads are only persisted by `CacheBackend::flush`
+    #[serde(default)]
+    pub accessed_at: u64,
+}
+
+impl IndexEntry {
+    fn eviction_key(&self, order: EvictionOrder) -> u64 {
+        match order {
+            EvictionOrder::Lru => self.accessed_at.max(self.fetched_at),
+            EvictionOrder::OldestFirst => self.fetched_at,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub entries: usize,
+    pub objects: usize,
+    /// unreadable entries and entries whose object is missing or corrupt
+    pub broken_entries: usize,
+    /// objects whose content does not match their hash
+    pub corrupt_objects: usize,
+    /// leftovers of interrupted writes
+    pub temp_files: usize,
+    /// intact objects no entry points to
+    pub unreferenced_objects: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Reclaimed {
+    pub entries: usize,
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+impl Display for Reclaimed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "removed {} entries and {} objects, reclaimed {} bytes",
+            self.entries, self.objects, self.bytes
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Recompressed {
+    pub entries: usize,
+    /// entries whose body could not be decompressed, they are left as they are
+    pub failed: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl Display for Recompressed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "recompressed {} entries from {} to {} bytes, {} failed",
+            self.entries, self.bytes_before, self.bytes_after, self.failed
+        )
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "checked {} entries and {} objects",
+            self.entries, self.objects
+        )?;
+        writeln!(f, "{} broken entries", self.broken_entries)?;
+        writeln!(f, "{} corrupt objects", self.corrupt_objects)?;
+        writeln!(f, "{} temporary files", self.temp_files)?;
+        writeln!(f, "{} unreferenced objects", self.unreferenced_objects)
+    }
+}
+
+/// Storage of fetched pages. Eviction and garbage collection are built on `entries` and
+/// `remove`, so a backend only has to provide storage and its own consistency checks.
+#[async_trait]
+pub trait CacheBackend: Send + Sync {
+    /// A corrupt body is a miss, so the next fetch replaces it.
+    async fn get(&self, key: &str) -> std::io::Result<Option<CachedPage>>;
+
+    /// Stores the page under `key`, the fingerprint of the request it answered.
+    async fn add(&self, key: &str, content: &[u8], metadata: &CacheMetadata)
+        -> std::io::Result<()>;
+
+    async fn update_metadata(&self, key: &str, metadata: &CacheMetadata) -> std::io::Result<()>;
+
+    fn entries(&self) -> Vec<IndexEntry>;
+
+    /// Persists the access times of reads, which are kept in memory until then so a
+    /// cache hit does not cost a write.
+    async fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Removes the entries and every body no remaining entry points to.
+    async fn remove(&self, keys: &[String]) -> std::io::Result<Reclaimed>;
+
+    /// Deletes stored data no entry points to anymore.
+    async fn sweep(&self) -> std::io::Result<Reclaimed> {
+        Ok(Reclaimed::default())
+    }
+
+    /// Checks every stored body against its hash, with `repair` broken entries are
+    /// deleted.
+    async fn verify(&self, repair: bool) -> std::io::Result<VerifyReport>;
+
+    fn stats(&self) -> CacheStats {
+        let entries = self.entries();
+        let mut objects = HashMap::new();
+        for entry in entries.iter() {
+            objects.insert(entry.object.as_str(), entry.size);
+        }
+        CacheStats {
+            entries: entries.len(),
+            objects: objects.len(),
+            bytes: objects.values().sum(),
+        }
+    }
+
+    /// Deletes entries older than the eviction max age, then entries in eviction order
+    /// until the bodies fit into the max size.
+    async fn evict(&self, policy: &EvictionPolicy) -> std::io::Result<Reclaimed> {
+        if policy.max_bytes.is_none() && policy.max_age.is_none() {
+            return Ok(Reclaimed::default());
+        }
+        let mut entries = self.entries();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.eviction_key(policy.order)));
+        let mut references: HashMap<String, (usize, u64)> = HashMap::new();
+        for entry in entries.iter() {
+            references
+                .entry(entry.object.clone())
+                .or_insert((0, entry.size))
+                .0 += 1;
+        }
+        let mut bytes: u64 = references.values().map(|(_, size)| size).sum();
+
+        // the bytes freed by dropping `entry`, its object may still be referenced
+        let mut release = |entry: &IndexEntry| {
+            let (count, size) = references.get_mut(&entry.object).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                *size
+            } else {
+                0
+            }
+        };
+
+        // expiry goes by fetch time, so recently read entries are no exception
+        let oldest = policy.max_age.map(|a| now().saturating_sub(a.as_secs()));
+        let (expired, mut entries): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|e| oldest.is_some_and(|o| e.fetched_at < o));
+        bytes -= expired.iter().map(&mut release).sum::<u64>();
+        let mut evicted: Vec<String> = expired.into_iter().map(|e| e.key).collect();
+        if let Some(max_bytes) = policy.max_bytes {
+            while bytes > max_bytes {
+                let Some(entry) = entries.pop() else {
+                    break;
+                };
+                bytes -= release(&entry);
+                evicted.push(entry.key);
+            }
+        }
+        self.remove(&evicted).await
+    }
+
+    /// Rewrites every entry stored with another codec than `compression` in place, the
+    /// replaced bodies are swept afterwards.
+    async fn recompress(&self, compression: &Compression) -> std::io::Result<Recompressed> {
+        let mut report = Recompressed::default();
+        for entry in self.entries() {
+            let Some(page) = self.get(&entry.key).await? else {
+                continue;
+            };
+            let mut metadata = page.metadata;
+            if metadata.codec == compression.codec {
+                continue;
+            }
+            let content = match metadata.codec.decompress(&page.content) {
+                Ok(content) => content,
+                Err(e) => {
+                    warn!(url = %entry.url, error = %e, "could not decompress cached page");
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            let recompressed = compression.compress(&content)?;
+            report.entries += 1;
+            report.bytes_before += page.content.len() as u64;
+            report.bytes_after += recompressed.len() as u64;
+            metadata.codec = compression.codec;
+            self.add(&entry.key, &recompressed, &metadata).await?;
+        }
+        self.sweep().await?;
+        Ok(report)
+    }
+
+    /// Deletes every entry whose key is not in `reachable`, usually the fingerprints the
+    /// last crawl has seen, along with all data no remaining entry points to.
+    async fn gc(&self, reachable: &HashSet<String>) -> std::io::Result<Reclaimed> {
+        let unreachable: Vec<String> = self
+            .entries()
+            .into_iter()
+            .filter(|e| !reachable.contains(&e.key))
+            .map(|e| e.key)
+            .collect();
+        let removed = self.remove(&unreachable).await?;
+        let swept = self.sweep().await?;
+        Ok(Reclaimed {
+            entries: removed.entries + swept.entries,
+            objects: removed.objects + swept.objects,
+            bytes: removed.bytes + swept.bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use reqwest::{header::HeaderMap, StatusCode, Url};
+
+    use super::{key, CacheBackend, CacheMetadata, EvictionOrder, EvictionPolicy};
+    use crate::{
+        codec::{Codec, Compression},
+        memory_cache::MemoryCache,
+    };
+
+    /// A directory of its own for the test `name`, emptied of earlier runs.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("atrico-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Caches `body` as the response to a GET of the wiki page `title`.
+    pub async fn add_page(cache: &dyn CacheBackend, title: &str, body: &[u8]) -> Url {
+        let url = Url::parse(&format!("https://worm.fandom.com/wiki/{title}")).unwrap();
+        let metadata = CacheMetadata::new(&url, StatusCode::OK, &HeaderMap::new());
+        cache.add(&key(&url), body, &metadata).await.unwrap();
+        url
+    }
+
+    /// One page per title, with the title as body.
+    pub async fn add_pages(cache: &dyn CacheBackend, titles: &[&str]) -> Vec<Url> {
+        let mut urls = Vec::new();
+        for title in titles {
+            urls.push(add_page(cache, title, title.as_bytes()).await);
+        }
+        urls
+    }
+
+    #[tokio::test]
+    async fn test_eviction_and_gc() {
+        let cache = MemoryCache::new();
+        let mut urls = Vec::new();
+        // B was fetched first and is evicted
+        for (page, fetched_at) in [("A", 3), ("B", 1), ("C", 2)] {
+            let url = add_page(&cache, page, &[fetched_at as u8; 10]).await;
+            let mut metadata = CacheMetadata::new(&url, StatusCode::OK, &HeaderMap::new());
+            metadata.fetched_at = fetched_at;
+            cache.update_metadata(&key(&url), &metadata).await.unwrap();
+            urls.push(url);
+        }
+        let policy = EvictionPolicy {
+            max_bytes: Some(20),
+            max_age: None,
+            order: EvictionOrder::OldestFirst,
+        };
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!(
+            (evicted.entries, evicted.objects, evicted.bytes),
+            (1, 1, 10)
+        );
+        assert!(cache.get(&key(&urls[1])).await.unwrap().is_none());
+
+        let reachable = [key(&urls[0])].into_iter().collect();
+        let collected = cache.gc(&reachable).await.unwrap();
+        assert_eq!((collected.entries, collected.bytes), (1, 10));
+        assert_eq!(cache.stats().entries, 1);
+        assert!(cache.get(&key(&urls[0])).await.unwrap().is_some());
+
+        // A was fetched long ago but read after D, it expires all the same
+        let fresh = add_page(&cache, "D", &[4; 10]).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(cache.get(&key(&urls[0])).await.unwrap().is_some());
+        let policy = EvictionPolicy {
+            max_bytes: None,
+            max_age: Some(Duration::from_secs(3600)),
+            order: EvictionOrder::Lru,
+        };
+        let evicted = cache.evict(&policy).await.unwrap();
+        assert_eq!((evicted.entries, evicted.bytes), (1, 10));
+        assert!(cache.get(&key(&urls[0])).await.unwrap().is_none());
+        assert!(cache.get(&key(&fresh)).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_recompress_zlib_entries() {
+        let cache = MemoryCache::new();
+        let url = Url::parse("https://worm.fandom.com/wiki/Weaver").unwrap();
+        let body = "Skitter ".repeat(100);
+        // written before the codec was recorded
+        let metadata: CacheMetadata = serde_json::from_value(serde_json::json!({
+            "url": url,
+            "fetched_at": 0,
+            "status": 200,
+            "headers": [],
+            "etag": null,
+            "last_modified": null,
+        }))
+        .unwrap();
+        let zlib = Codec::Zlib.compress(body.as_bytes(), 9).unwrap();
+        cache.add(&key(&url), &zlib, &metadata).await.unwrap();
+
+        let zstd = Compression {
+            codec: Codec::Zstd,
+            level: None,
+        };
+        let recompressed = cache.recompress(&zstd).await.unwrap();
+        assert_eq!(recompressed.entries, 1);
+        let page = cache.get(&key(&url)).await.unwrap().unwrap();
+        assert_eq!(page.metadata.codec, Codec::Zstd);
+        assert_eq!(
+            Codec::Zstd.decompress(&page.content).unwrap(),
+            body.as_bytes()
+        );
+        assert_eq!(cache.recompress(&zstd).await.unwrap().entries, 0);
+    }
+}
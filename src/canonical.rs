@@ -0,0 +1,168 @@
+use reqwest::Url;
+use serde::Deserialize;
+
+/// Rewrites urls into one canonical form before the spider dedups and fetches them, so
+/// variants of the same page are only crawled and cached once.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Canonicalizer {
+    pub strip_fragment: bool,
+    /// decode escaped unreserved characters and upper case the remaining escapes
+    pub normalize_encoding: bool,
+    pub strip_trailing_slash: bool,
+    /// mediawiki redirects `/wiki/Foo_` to `/wiki/Foo`
+    pub strip_trailing_underscore: bool,
+    /// query parameters to keep, `*` keeps all of them
+    pub query_allowlist: Vec<String>,
+    pub sort_query: bool,
+}
+
+impl Default for Canonicalizer {
+    fn default() -> Self {
+        Canonicalizer {
+            strip_fragment: true,
+            normalize_encoding: true,
+            strip_trailing_slash: false,
+            strip_trailing_underscore: false,
+            // mostly views of the same page, like `?action=edit` or `?oldid=`
+            query_allowlist: Vec::new(),
+            sort_query: true,
+        }
+    }
+}
+
+impl Canonicalizer {
+    pub fn apply(&self, mut url: Url) -> Url {
+        if self.strip_fragment {
+            url.set_fragment(None);
+        }
+        // special schemes already come with a lower case host
+        if let Some(host) = url.host_str().map(str::to_lowercase) {
+            let _ = url.set_host(Some(&host));
+        }
+
+        let mut path = url.path().to_string();
+        if self.normalize_encoding {
+            path = normalize_escapes(&path);
+        }
+        if self.strip_trailing_slash {
+            while path.len() > 1 && path.ends_with('/') {
+                path.pop();
+            }
+        }
+        if self.strip_trailing_underscore {
+            while path.ends_with('_') {
+                path.pop();
+            }
+        }
+        url.set_path(&path);
+
+        let keep_all = self.query_allowlist.iter().any(|a| a == "*");
+        if url.query().is_some() && (!keep_all || self.sort_query) {
+            let mut pairs: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(k, _)| keep_all || self.query_allowlist.iter().any(|a| a == k))
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect();
+            if self.sort_query {
+                pairs.sort();
+            }
+            if pairs.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(pairs);
+            }
+        }
+        url
+    }
+}
+
+/// `%7e` and `~` are the same character, as are `%2f` and `%2F`.
+fn normalize_escapes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    // a serialized url is ascii, byte offsets are char offsets
+    while i < s.len() {
+        let escape = s.get(i + 1..i + 3).filter(|_| s.as_bytes()[i] == b'%');
+        match escape.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                out.push(byte as char);
+                i += 3;
+            }
+            Some(_) => {
+                out.push('%');
+                out.push_str(&s[i + 1..i + 3].to_uppercase());
+                i += 3;
+            }
+            None => {
+                out.push_str(&s[i..i + 1]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::Url;
+
+    use super::Canonicalizer;
+
+    fn canonical(canonicalizer: &Canonicalizer, url: &str) -> String {
+        canonicalizer.apply(Url::parse(url).unwrap()).to_string()
+    }
+
+    #[test]
+    fn test_variants_collapse() {
+        let c = Canonicalizer {
+            strip_trailing_slash: true,
+            strip_trailing_underscore: true,
+            ..Canonicalizer::default()
+        };
+        let expected = "https://worm.fandom.com/wiki/Taylor_Hebert";
+        for variant in [
+            "https://worm.fandom.com/wiki/Taylor_Hebert",
+            "https://WORM.fandom.com/wiki/Taylor_Hebert#Abilities",
+            "https://worm.fandom.com/wiki/Taylor%5fHebert",
+            "https://worm.fandom.com/wiki/Taylor_Hebert/",
+            "https://worm.fandom.com/wiki/Taylor_Hebert_",
+        ] {
+            assert_eq!(canonical(&c, variant), expected, "{variant}");
+        }
+        assert_eq!(
+            canonical(&c, "https://worm.fandom.com/wiki/A%2fB"),
+            "https://worm.fandom.com/wiki/A%2FB"
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let sorted = Canonicalizer {
+            query_allowlist: vec!["*".into()],
+            ..Canonicalizer::default()
+        };
+        assert_eq!(
+            canonical(&sorted, "https://worm.fandom.com/api.php?b=2&a=1"),
+            "https://worm.fandom.com/api.php?a=1&b=2"
+        );
+        let allowlist = Canonicalizer {
+            query_allowlist: vec!["action".into()],
+            ..Canonicalizer::default()
+        };
+        assert_eq!(
+            canonical(
+                &allowlist,
+                "https://worm.fandom.com/api.php?so=x&action=parse"
+            ),
+            "https://worm.fandom.com/api.php?action=parse"
+        );
+        assert_eq!(
+            canonical(
+                &Canonicalizer::default(),
+                "https://worm.fandom.com/wiki/Skitter?file=x"
+            ),
+            "https://worm.fandom.com/wiki/Skitter"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::io::{Read, Write};
+
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+use serde::{Deserialize, Serialize};
+
+/// How a cached body is compressed, recorded per entry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// entries written before the codec was recorded are zlib
+    #[default]
+    Zlib,
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl Codec {
+    /// zlib and gzip at their best compression as before the codec was configurable.
+    pub fn default_level(self) -> u32 {
+        match self {
+            Codec::Zlib | Codec::Gzip => 9,
+            Codec::Zstd => 3,
+            Codec::None => 0,
+        }
+    }
+
+    pub fn compress(self, content: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+        match self {
+            Codec::Zlib => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+            Codec::Zstd => zstd::encode_all(content, level as i32),
+            Codec::None => Ok(content.to_vec()),
+        }
+    }
+
+    pub fn decompress(self, content: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        match self {
+            Codec::Zlib => {
+                ZlibDecoder::new(content).read_to_end(&mut decompressed)?;
+            }
+            Codec::Gzip => {
+                GzDecoder::new(content).read_to_end(&mut decompressed)?;
+            }
+            Codec::Zstd => decompressed = zstd::decode_all(content)?,
+            Codec::None => decompressed.extend(content),
+        }
+        Ok(decompressed)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Compression {
+    pub codec: Codec,
+    /// None uses the default level of the codec
+    pub level: Option<u32>,
+}
+
+impl Compression {
+    pub fn compress(&self, content: &[u8]) -> std::io::Result<Vec<u8>> {
+        let level = self.level.unwrap_or(self.codec.default_level());
+        self.codec.compress(content, level)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Codec;
+
+    #[test]
+    fn test_round_trip() {
+        let page = "<html><body>Skitter, Weaver</body></html>".repeat(100);
+        for codec in [Codec::Zlib, Codec::Gzip, Codec::Zstd, Codec::None] {
+            let compressed = codec
+                .compress(page.as_bytes(), codec.default_level())
+                .unwrap();
+            assert_eq!(codec.decompress(&compressed).unwrap(), page.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
+
+use reqwest::{
+    header::{HeaderMap, HeaderName},
+    Method, StatusCode, Url,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    cache::{CachePolicy, EvictionOrder, EvictionPolicy},
+    canonical::Canonicalizer,
+    codec::{Codec, Compression},
+    fingerprint::Fingerprinter,
+    frontier::FrontierKind,
+    rate_limit::{HostLimits, RateLimits, MAX_INTERVAL},
+    requester::SimpleRequest,
+    retry::RetryPolicy,
+    worm_wiki,
+};
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid config: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("invalid retry status {0}")]
+    Status(u16),
+    #[error("invalid fingerprint header {0}")]
+    Header(String),
+    #[error("invalid requests_per_second {0}, use 0 or at least one request per hour")]
+    Rate(f64),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParserKind {
+    WormWiki,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheBackendKind {
+    /// a directory of content addressed files
+    #[default]
+    Fs,
+    /// a single append only file
+    Store,
+    /// nothing survives the process
+    Memory,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub seeds: Vec<Url>,
+    pub allowed_prefixes: Vec<String>,
+    pub parser: ParserKind,
+    pub frontier: FrontierKind,
+    /// links followed from a seed at most, leave out for no limit
+    pub max_depth: Option<u32>,
+    /// requests fetched and parsed at once
+    pub max_tasks: usize,
+    /// page bytes running requests may hold before no new one is started, 0 disables
+    /// the limit
+    pub max_buffered_bytes: usize,
+    /// how long running requests may take to finish after ctrl-c, a second one stops at
+    /// once
+    pub shutdown_timeout_secs: u64,
+    pub canonical: Canonicalizer,
+    /// request headers that tell otherwise equal requests apart, besides method, url
+    /// and body
+    pub fingerprint_headers: Vec<String>,
+    pub cache_backend: CacheBackendKind,
+    pub cache_dir: PathBuf,
+    pub cache_policy: CachePolicyConfig,
+    pub output_dir: PathBuf,
+    pub state_dir: PathBuf,
+    pub log_file: PathBuf,
+    /// serve prometheus metrics on `http://<addr>/metrics` during crawls, leave out to
+    /// disable
+    pub metrics_addr: Option<SocketAddr>,
+    pub retry: RetryConfig,
+    pub rate_limits: RateLimitConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            seeds: vec![Url::parse(worm_wiki::SEED).unwrap()],
+            allowed_prefixes: vec![worm_wiki::PREFIX.into()],
+            parser: ParserKind::WormWiki,
+            frontier: FrontierKind::default(),
+            max_depth: None,
+            max_tasks: 16,
+            max_buffered_bytes: 256 * 1024 * 1024,
+            shutdown_timeout_secs: 30,
+            canonical: Canonicalizer::default(),
+            fingerprint_headers: Vec::new(),
+            cache_backend: CacheBackendKind::default(),
+            cache_dir: "page_cache".into(),
+            cache_policy: CachePolicyConfig::default(),
+            output_dir: "characters".into(),
+            state_dir: "spider_state".into(),
+            log_file: "log.jsonl".into(),
+            metrics_addr: None,
+            retry: RetryConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &PathBuf) -> Result<Config, ConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&content)?;
+        config.retry.policy()?;
+        config.try_fingerprinter()?;
+        config.rate_limits.validate()?;
+        Ok(config)
+    }
+
+    pub fn initial_requests(&self) -> Vec<SimpleRequest> {
+        self.seeds
+            .iter()
+            .map(|url| SimpleRequest {
+                method: Method::GET,
+                url: url.clone(),
+                headers: HeaderMap::new(),
+                body: None,
+                depth: 0,
+                referrer: None,
+            })
+            .collect()
+    }
+
+    pub fn cache_policy(&self) -> CachePolicy {
+        CachePolicy {
+            max_age: self.cache_policy.max_age_secs.map(Duration::from_secs),
+            revalidate: self.cache_policy.revalidate,
+            eviction: EvictionPolicy {
+                max_bytes: self.cache_policy.max_bytes,
+                max_age: self.cache_policy.evict_after_secs.map(Duration::from_secs),
+                order: self.cache_policy.eviction,
+            },
+            compression: Compression {
+                codec: self.cache_policy.codec,
+                level: self.cache_policy.level,
+            },
+        }
+    }
+
+    pub fn fingerprinter(&self) -> Fingerprinter {
+        // validated in load
+        self.try_fingerprinter().unwrap()
+    }
+
+    fn try_fingerprinter(&self) -> Result<Fingerprinter, ConfigError> {
+        Ok(Fingerprinter {
+            headers: self
+                .fingerprint_headers
+                .iter()
+                .map(|h| HeaderName::try_from(h).map_err(|_| ConfigError::Header(h.clone())))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        // validated in load
+        self.retry.policy().unwrap()
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            max_in_flight: self.rate_limits.max_in_flight,
+            default_host: self.rate_limits.default_host.clone().into(),
+            hosts: self
+                .rate_limits
+                .hosts
+                .iter()
+                .map(|(host, limits)| (host.clone(), limits.clone().into()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CachePolicyConfig {
+    /// leave out to never consider cached pages stale
+    pub max_age_secs: Option<u64>,
+    pub revalidate: bool,
+    /// leave out to let the cache grow without limit
+    pub max_bytes: Option<u64>,
+    /// entries fetched longer ago are deleted instead of revalidated
+    pub evict_after_secs: Option<u64>,
+    pub eviction: EvictionOrder,
+    pub codec: Codec,
+    /// leave out for the default level of the codec
+    pub level: Option<u32>,
+}
+
+impl Default for CachePolicyConfig {
+    fn default() -> Self {
+        let policy = CachePolicy::default();
+        CachePolicyConfig {
+            max_age_secs: policy.max_age.map(|a| a.as_secs()),
+            revalidate: policy.revalidate,
+            max_bytes: policy.eviction.max_bytes,
+            evict_after_secs: policy.eviction.max_age.map(|a| a.as_secs()),
+            eviction: policy.eviction.order,
+            codec: policy.compression.codec,
+            level: policy.compression.level,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub jitter: f64,
+    pub statuses: Vec<u16>,
+    pub timeouts: bool,
+    pub connect_errors: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        RetryConfig {
+            max_attempts: policy.max_attempts,
+            base_delay_ms: policy.base_delay.as_millis() as u64,
+            max_delay_ms: policy.max_delay.as_millis() as u64,
+            jitter: policy.jitter,
+            statuses: policy.retry_statuses.iter().map(|s| s.as_u16()).collect(),
+            timeouts: policy.retry_timeouts,
+            connect_errors: policy.retry_connect_errors,
+        }
+    }
+}
+
+impl RetryConfig {
+    fn policy(&self) -> Result<RetryPolicy, ConfigError> {
+        Ok(RetryPolicy {
+            max_attempts: self.max_attempts.max(1),
+            base_delay: Duration::from_millis(self.base_delay_ms),
+            max_delay: Duration::from_millis(self.max_delay_ms),
+            jitter: self.jitter,
+            retry_statuses: self
+                .statuses
+                .iter()
+                .map(|s| StatusCode::from_u16(*s).map_err(|_| ConfigError::Status(*s)))
+                .collect::<Result<_, _>>()?,
+            retry_timeouts: self.timeouts,
+            retry_connect_errors: self.connect_errors,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_in_flight: usize,
+    pub default_host: HostLimitConfig,
+    pub hosts: HashMap<String, HostLimitConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let limits = RateLimits::default();
+        RateLimitConfig {
+            max_in_flight: limits.max_in_flight,
+            default_host: HostLimitConfig::default(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostLimitConfig {
+    /// 0 disables the rate limit for the host
+    pub requests_per_second: f64,
+    pub burst: u32,
+    pub max_in_flight: usize,
+}
+
+impl RateLimitConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        std::iter::once(&self.default_host)
+            .chain(self.hosts.values())
+            .try_for_each(HostLimitConfig::validate)
+    }
+}
+
+impl HostLimitConfig {
+    /// slower rates would have requests wait longer than a `Duration` can hold
+    fn validate(&self) -> Result<(), ConfigError> {
+        let rate = self.requests_per_second;
+        if rate == 0.0 || rate.is_finite() && rate >= 1.0 / MAX_INTERVAL.as_secs_f64() {
+            Ok(())
+        } else {
+            Err(ConfigError::Rate(rate))
+        }
+    }
+}
+
+impl Default for HostLimitConfig {
+    fn default() -> Self {
+        let limits = HostLimits::default();
+        HostLimitConfig {
+            requests_per_second: limits.requests_per_second.unwrap_or_default(),
+            burst: limits.burst,
+            max_in_flight: limits.max_in_flight,
+        }
+    }
+}
+
+impl From<HostLimitConfig> for HostLimits {
+    fn from(value: HostLimitConfig) -> Self {
+        HostLimits {
+            requests_per_second: Some(value.requests_per_second).filter(|r| *r > 0.0),
+            burst: value.burst,
+            max_in_flight: value.max_in_flight,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Config;
+
+    #[test]
+    fn test_example_config() {
+        let config: Config = toml::from_str(include_str!("../crawl.example.toml")).unwrap();
+        let limits = config.rate_limits();
+        assert_eq!(
+            limits.hosts["worm.fandom.com"].requests_per_second,
+            Some(2.0)
+        );
+        assert_eq!(config.retry_policy().retry_statuses.len(), 5);
+    }
+
+    #[test]
+    fn test_partial_canonical_table() {
+        let config: Config = toml::from_str("[canonical]\nstrip_trailing_slash = true").unwrap();
+        assert!(config.canonical.strip_trailing_slash);
+        assert!(config.canonical.query_allowlist.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limits_are_validated() {
+        let validate = |table: &str| {
+            let config: Config = toml::from_str(table).unwrap();
+            config.rate_limits.validate()
+        };
+        assert!(validate("[rate_limits.default_host]\nrequests_per_second = 0").is_ok());
+        assert!(validate("[rate_limits.default_host]\nrequests_per_second = 0.5").is_ok());
+        assert!(validate("[rate_limits.default_host]\nrequests_per_second = -1").is_err());
+        assert!(validate("[rate_limits.hosts.\"a.com\"]\nrequests_per_second = 1e-300").is_err());
+        assert!(validate("[rate_limits.hosts.\"a.com\"]\nrequests_per_second = nan").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::{fmt::Display, time::Duration};
+
+use reqwest::{StatusCode, Url};
+use thiserror::Error;
+
+use crate::stats::CrawlStats;
+
+#[derive(Debug, Error)]
+pub enum CrawlError {
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("unexpected status {status}")]
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("cache access failed: {0}")]
+    Cache(#[from] std::io::Error),
+    /// offline and the page is not in the cache
+    #[error("not cached")]
+    NotCached,
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("no matching layout")]
+    NoMatchingLayout,
+    #[error("too many matching layouts: {0:?}")]
+    TooManyMatchingLayouts(Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+    Fetch,
+    Cache,
+    Parse,
+    Task,
+}
+
+impl CrawlError {
+    pub fn stage(&self) -> Stage {
+        match self {
+            CrawlError::Request(_) | CrawlError::Status { .. } => Stage::Fetch,
+            CrawlError::Cache(_) | CrawlError::NotCached => Stage::Cache,
+            CrawlError::Parse(_) => Stage::Parse,
+            CrawlError::Task(_) => Stage::Task,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub url: Url,
+    pub stage: Stage,
+    pub cause: String,
+}
+
+#[derive(Debug, Default)]
+pub struct CrawlReport {
+    pub succeeded: usize,
+    pub failures: Vec<Failure>,
+    /// set for crawls served only from the cache
+    pub offline: bool,
+    pub not_cached: Vec<Url>,
+    /// stopped by a signal before the frontier was empty
+    pub interrupted: bool,
+    /// requests still running when the shutdown timed out
+    pub abandoned: usize,
+    pub stats: CrawlStats,
+}
+
+impl CrawlReport {
+    pub fn record(&mut self, url: Url, error: &CrawlError) {
+        if let CrawlError::NotCached = error {
+            self.not_cached.push(url);
+            return;
+        }
+        self.failures.push(Failure {
+            url,
+            stage: error.stage(),
+            cause: error.to_string(),
+        });
+    }
+
+    /// Share of requested pages that were found in the cache.
+    pub fn coverage(&self) -> f64 {
+        let cached = self.succeeded + self.failures.len();
+        let requested = cached + self.not_cached.len();
+        if requested == 0 {
+            return 1.0;
+        }
+        cached as f64 / requested as f64
+    }
+}
+
+impl Display for CrawlReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "crawled {} pages, {} failed",
+            self.succeeded,
+            self.failures.len()
+        )?;
+        for failure in self.failures.iter() {
+            writeln!(
+                f,
+                "  {:?} {}: {}",
+                failure.stage, failure.url, failure.cause
+            )?;
+        }
+        if self.offline {
+            writeln!(
+                f,
+                "{} pages not cached, coverage {:.1}%",
+                self.not_cached.len(),
+                self.coverage() * 100.0
+            )?;
+            for url in self.not_cached.iter() {
+                writeln!(f, "  not cached {url}")?;
+            }
+        }
+        if self.interrupted {
+            writeln!(
+                f,
+                "interrupted, {} queued and {} abandoned requests are left for resume",
+                self.stats.queued, self.abandoned
+            )?;
+        }
+        write!(f, "{}", self.stats)
+    }
+}
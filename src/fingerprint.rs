@@ -0,0 +1,106 @@
+use reqwest::{header::HeaderName, Method};
+use sha2::{Digest, Sha256};
+
+use crate::{cache, requester::SimpleRequest};
+
+/// Identifies a request by method, url, selected headers and body. The spider dedups
+/// on it and the cache stores responses under it, so a POST to `api.php` neither
+/// collides with a GET of the same url nor with a POST of another body.
+#[derive(Clone, Debug, Default)]
+pub struct Fingerprinter {
+    /// request headers that change the response, e.g. `accept-language`
+    pub headers: Vec<HeaderName>,
+}
+
+impl Fingerprinter {
+    /// A GET without a body and without any of the selected headers is keyed by its url
+    /// alone, so caches and checkpoints written before fingerprinting still match.
+    pub fn fingerprint(&self, request: &SimpleRequest) -> String {
+        let mut selected: Vec<(&str, &[u8])> = self
+            .headers
+            .iter()
+            .flat_map(|name| {
+                request
+                    .headers
+                    .get_all(name)
+                    .iter()
+                    .map(|value| (name.as_str(), value.as_bytes()))
+            })
+            .collect();
+        if request.method == Method::GET && request.body.is_none() && selected.is_empty() {
+            return cache::key(&request.url);
+        }
+        selected.sort();
+
+        let mut hasher = Sha256::new();
+        hasher.update(request.method.as_str());
+        hasher.update(b"\n");
+        hasher.update(request.url.as_str());
+        hasher.update(b"\n");
+        // header values cannot contain newlines
+        for (name, value) in selected {
+            hasher.update(name);
+            hasher.update(b":");
+            hasher.update(value);
+            hasher.update(b"\n");
+        }
+        hasher.update(b"\n");
+        if let Some(body) = &request.body {
+            hasher.update(body);
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::{
+        header::{HeaderMap, HeaderValue, ACCEPT_LANGUAGE, USER_AGENT},
+        Method, Url,
+    };
+
+    use super::Fingerprinter;
+    use crate::{cache, requester::SimpleRequest};
+
+    fn request(method: Method, body: Option<&str>) -> SimpleRequest {
+        SimpleRequest {
+            method,
+            url: Url::parse("https://worm.fandom.com/api.php").unwrap(),
+            headers: HeaderMap::new(),
+            body: body.map(String::from),
+            depth: 0,
+            referrer: None,
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let f = Fingerprinter {
+            headers: vec![ACCEPT_LANGUAGE],
+        };
+        let get = request(Method::GET, None);
+        assert_eq!(f.fingerprint(&get), cache::key(&get.url));
+
+        let parse = request(Method::POST, Some("action=parse&page=Skitter"));
+        let query = request(Method::POST, Some("action=query&titles=Skitter"));
+        let empty = request(Method::POST, None);
+        let fingerprints = [&get, &parse, &query, &empty].map(|r| f.fingerprint(r));
+        for (i, a) in fingerprints.iter().enumerate() {
+            for b in &fingerprints[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+
+        let mut german = request(Method::GET, None);
+        german
+            .headers
+            .insert(ACCEPT_LANGUAGE, HeaderValue::from_static("de"));
+        assert_ne!(f.fingerprint(&german), f.fingerprint(&get));
+        // headers that are not selected do not matter
+        let mut agent = request(Method::GET, None);
+        agent
+            .headers
+            .insert(USER_AGENT, HeaderValue::from_static("atrico"));
+        assert_eq!(f.fingerprint(&agent), f.fingerprint(&get));
+    }
+}
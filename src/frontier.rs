@@ -0,0 +1,213 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, VecDeque},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::requester::SimpleRequest;
+
+/// A request waiting in the frontier.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Queued {
+    #[serde(flatten)]
+    pub request: SimpleRequest,
+    /// higher is crawled earlier, only the priority frontier looks at it
+    #[serde(default)]
+    pub priority: i64,
+}
+
+/// Decides which open request the spider fetches next.
+pub trait Frontier: Send {
+    fn push(&mut self, queued: Queued);
+    fn pop(&mut self) -> Option<Queued>;
+    fn len(&self) -> usize;
+    /// Everything still queued in the order it was pushed, pushing it into an empty
+    /// frontier of the same kind restores it.
+    fn queued(&self) -> Vec<Queued>;
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrontierKind {
+    /// breadth first, oldest request first
+    Bfs,
+    /// depth first, newest request first
+    Dfs,
+    /// highest priority first, breadth first among equal priorities
+    #[default]
+    Priority,
+}
+
+impl FrontierKind {
+    pub fn build(self) -> Box<dyn Frontier> {
+        match self {
+            FrontierKind::Bfs => Box::<BfsFrontier>::default(),
+            FrontierKind::Dfs => Box::<DfsFrontier>::default(),
+            FrontierKind::Priority => Box::<PriorityFrontier>::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BfsFrontier {
+    queue: VecDeque<Queued>,
+}
+
+impl Frontier for BfsFrontier {
+    fn push(&mut self, queued: Queued) {
+        self.queue.push_back(queued);
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        self.queue.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn queued(&self) -> Vec<Queued> {
+        self.queue.iter().cloned().collect()
+    }
+}
+
+#[derive(Default)]
+pub struct DfsFrontier {
+    stack: Vec<Queued>,
+}
+
+impl Frontier for DfsFrontier {
+    fn push(&mut self, queued: Queued) {
+        self.stack.push(queued);
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        self.stack.pop()
+    }
+
+    fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn queued(&self) -> Vec<Queued> {
+        self.stack.clone()
+    }
+}
+
+struct Prioritized {
+    queued: Queued,
+    /// insertion order, keeps requests of equal priority first in first out
+    seq: u64,
+}
+
+impl Ord for Prioritized {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.queued
+            .priority
+            .cmp(&other.queued.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Prioritized {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Prioritized {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Prioritized {}
+
+#[derive(Default)]
+pub struct PriorityFrontier {
+    heap: BinaryHeap<Prioritized>,
+    seq: u64,
+}
+
+impl Frontier for PriorityFrontier {
+    fn push(&mut self, queued: Queued) {
+        self.heap.push(Prioritized {
+            queued,
+            seq: self.seq,
+        });
+        self.seq += 1;
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        self.heap.pop().map(|p| p.queued)
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    fn queued(&self) -> Vec<Queued> {
+        let mut queued: Vec<&Prioritized> = self.heap.iter().collect();
+        queued.sort_by_key(|p| p.seq);
+        queued.into_iter().map(|p| p.queued.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use reqwest::{header::HeaderMap, Method, Url};
+
+    use super::{FrontierKind, Queued};
+    use crate::requester::SimpleRequest;
+
+    fn queued(page: &str, priority: i64) -> Queued {
+        Queued {
+            request: SimpleRequest {
+                method: Method::GET,
+                url: Url::parse(&format!("https://worm.fandom.com/wiki/{page}")).unwrap(),
+                headers: HeaderMap::new(),
+                body: None,
+                depth: 0,
+                referrer: None,
+            },
+            priority,
+        }
+    }
+
+    fn order(kind: FrontierKind) -> Vec<String> {
+        let mut frontier = kind.build();
+        for (page, priority) in [("A", 0), ("B", 2), ("C", 0), ("D", 1)] {
+            frontier.push(queued(page, priority));
+        }
+        let mut restored = kind.build();
+        for q in frontier.queued() {
+            restored.push(q);
+        }
+        let mut popped = Vec::new();
+        while let Some(q) = frontier.pop() {
+            assert_eq!(restored.pop().unwrap().request.url, q.request.url);
+            popped.push(q.request.url.path().to_string());
+        }
+        popped
+            .into_iter()
+            .map(|p| p.trim_start_matches("/wiki/").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_pop_order() {
+        assert_eq!(order(FrontierKind::Bfs), ["A", "B", "C", "D"]);
+        assert_eq!(order(FrontierKind::Dfs), ["D", "C", "B", "A"]);
+        assert_eq!(order(FrontierKind::Priority), ["B", "D", "A", "C"]);
+    }
+
+    #[test]
+    fn test_checkpoint_without_priority() {
+        let old =
+            r#"{"method":"GET","url":"https://worm.fandom.com/wiki/A","headers":[],"body":null}"#;
+        let q: Queued = serde_json::from_str(old).unwrap();
+        assert_eq!(q.priority, 0);
+        assert_eq!(q.request.url.path(), "/wiki/A");
+    }
+}
@@ -0,0 +1,667 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+use async_trait::async_trait;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::RwLock,
+};
+use tracing::{info, warn};
+
+use crate::{
+    cache::{
+        key, now, write_atomic, CacheBackend, CacheMetadata, CachedPage, IndexEntry, Reclaimed,
+        VerifyReport, TMP_SUFFIX,
+    },
+    codec::Codec,
+};
+
+/// What is stored per url, the body itself lives in the object store so identical
+/// bodies are only kept once.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    object: String,
+    size: u64,
+    metadata: CacheMetadata,
+}
+
+/// Layout below `base_dir`:
+/// - `objects/<2 hex>/<sha256 of content>` the compressed bodies
+/// - `entries/<2 hex>/<sha256 of url>.json` metadata and object of a url
+/// - `index.jsonl` append only log of index entries, the last line for a key wins
+///
+/// Entries are independent files, so instead of one lock for the whole cache every
+/// entry and object is guarded by one of `LOCK_STRIPES` read write locks picked by
+/// its hash.
+pub struct FsCache {
+    base_dir: PathBuf,
+    index: Mutex<HashMap<String, IndexEntry>>,
+    index_log: tokio::sync::Mutex<()>,
+    /// an access time changed since the index was last written
+    read_since_flush: AtomicBool,
+    locks: Vec<RwLock<()>>,
+}
+
+impl FsCache {
+    const INDEX_FILE: &'static str = "index.jsonl";
+    const OBJECTS_DIR: &'static str = "objects";
+    const ENTRIES_DIR: &'static str = "entries";
+    const LEGACY_METADATA_SUFFIX: &'static str = ".meta.json";
+    const LOCK_STRIPES: usize = 256;
+
+    pub async fn open(base_dir: PathBuf) -> std::io::Result<FsCache> {
+        fs::create_dir_all(&base_dir).await?;
+        let cache = FsCache {
+            index: Mutex::new(Self::read_index(&base_dir.join(Self::INDEX_FILE)).await?),
+            base_dir,
+            index_log: tokio::sync::Mutex::new(()),
+            read_since_flush: AtomicBool::new(false),
+            locks: (0..Self::LOCK_STRIPES).map(|_| RwLock::new(())).collect(),
+        };
+        cache.compact_index().await?;
+        cache.migrate_legacy().await?;
+        Ok(cache)
+    }
+
+    /// Names that are not hashes, like stray files found by `verify`, share a stripe.
+    fn lock(&self, hash: &str) -> &RwLock<()> {
+        let stripe = hash
+            .get(..2)
+            .and_then(|s| usize::from_str_radix(s, 16).ok())
+            .unwrap_or_default();
+        &self.locks[stripe % self.locks.len()]
+    }
+
+    fn sharded(&self, dir: &str, name: &str) -> PathBuf {
+        let shard = name.get(..2).unwrap_or(name);
+        self.base_dir.join(dir).join(shard).join(name)
+    }
+
+    /// Entries and objects are named by sha256 hashes, anything else is not ours.
+    fn is_hash(name: &str) -> bool {
+        name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.sharded(Self::ENTRIES_DIR, &format!("{key}.json"))
+    }
+
+    fn object_path(&self, object: &str) -> PathBuf {
+        self.sharded(Self::OBJECTS_DIR, object)
+    }
+
+    /// An unreadable entry is removed and treated as missing.
+    async fn read_entry(&self, key: &str) -> std::io::Result<Option<Entry>> {
+        let path = self.entry_path(key);
+        let content = {
+            let _lock = self.lock(key).read().await;
+            match fs::read(&path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        };
+        match serde_json::from_slice(&content) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) => {
+                warn!(key, error = %e, "corrupt cache entry, removing it");
+                let _lock = self.lock(key).write().await;
+                Self::remove_if_exists(&path).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn write_entry(&self, key: &str, entry: &Entry) -> std::io::Result<()> {
+        {
+            let _lock = self.lock(key).write().await;
+            write_atomic(&self.entry_path(key), &serde_json::to_vec(entry)?).await?;
+        }
+
+        let index_entry = IndexEntry {
+            url: entry.metadata.url.clone(),
+            key: key.into(),
+            object: entry.object.clone(),
+            fetched_at: entry.metadata.fetched_at,
+            size: entry.size,
+            accessed_at: now(),
+        };
+        let mut line = serde_json::to_vec(&index_entry)?;
+        line.push(b'\n');
+        let _log = self.index_log.lock().await;
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.base_dir.join(Self::INDEX_FILE))
+            .await?;
+        index.write_all(&line).await?;
+        self.index.lock().unwrap().insert(key.into(), index_entry);
+        Ok(())
+    }
+
+    async fn read_index(path: &PathBuf) -> std::io::Result<HashMap<String, IndexEntry>> {
+        let content = match fs::read_to_string(path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let mut index = HashMap::new();
+        // a torn last line from a crash is skipped, the entry file is still authoritative
+        for entry in content
+            .lines()
+            .flat_map(|l| serde_json::from_str::<IndexEntry>(l).ok())
+        {
+            index.insert(entry.key.clone(), entry);
+        }
+        Ok(index)
+    }
+
+    /// Rewrites the index with one line per entry.
+    async fn compact_index(&self) -> std::io::Result<()> {
+        let path = self.base_dir.join(Self::INDEX_FILE);
+        let mut content = Vec::new();
+        for entry in self.index.lock().unwrap().values() {
+            content.extend(serde_json::to_vec(entry)?);
+            content.push(b'\n');
+        }
+        write_atomic(&path, &content).await
+    }
+
+    async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Files of a sharded directory, `dir/<2 hex>/<name>`.
+    async fn sharded_files(&self, dir: &str) -> std::io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        let mut shards = match fs::read_dir(self.base_dir.join(dir)).await {
+            Ok(shards) => shards,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(files),
+            Err(e) => return Err(e),
+        };
+        while let Some(shard) = shards.next_entry().await? {
+            if !shard.file_type().await?.is_dir() {
+                continue;
+            }
+            let mut entries = fs::read_dir(shard.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    /// Moves entries of the old flat layout, url encoded file names next to an optional
+    /// `.meta.json`, into the content addressed layout.
+    async fn migrate_legacy(&self) -> std::io::Result<()> {
+        let mut migrated = 0;
+        let mut files = fs::read_dir(&self.base_dir).await?;
+        while let Some(file) = files.next_entry().await? {
+            let name = file.file_name().to_string_lossy().to_string();
+            if !file.file_type().await?.is_file()
+                || name.starts_with(Self::INDEX_FILE)
+                || name.ends_with(Self::LEGACY_METADATA_SUFFIX)
+            {
+                continue;
+            }
+            let Some(url) = urlencoding::decode(&name)
+                .ok()
+                .and_then(|u| Url::parse(&u).ok())
+            else {
+                continue;
+            };
+            let content = fs::read(file.path()).await?;
+            let metadata_path = self
+                .base_dir
+                .join(format!("{name}{}", Self::LEGACY_METADATA_SUFFIX));
+            let stored = match fs::read(&metadata_path).await {
+                Ok(m) => serde_json::from_slice(&m)
+                    .map_err(|e| warn!(url = %url, error = %e, "corrupt legacy cache metadata"))
+                    .ok(),
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            let metadata = match stored {
+                Some(metadata) => metadata,
+                None => CacheMetadata {
+                    url: url.clone(),
+                    fetched_at: file
+                        .metadata()
+                        .await?
+                        .modified()?
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    status: StatusCode::OK.as_u16(),
+                    headers: Vec::new(),
+                    etag: None,
+                    last_modified: None,
+                    codec: Codec::Zlib,
+                },
+            };
+            self.add(&key(&url), &content, &metadata).await?;
+            fs::remove_file(file.path()).await?;
+            if let Err(e) = fs::remove_file(metadata_path).await {
+                if e.kind() != ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+            migrated += 1;
+        }
+        if migrated > 0 {
+            info!(migrated, "migrated legacy cache entries");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CacheBackend for FsCache {
+    /// A corrupt object is also removed.
+    async fn get(&self, key: &str) -> std::io::Result<Option<CachedPage>> {
+        let Some(entry) = self.read_entry(key).await? else {
+            return Ok(None);
+        };
+        let lock = self.lock(&entry.object);
+        let path = self.object_path(&entry.object);
+        let content = {
+            let _lock = lock.read().await;
+            match fs::read(&path).await {
+                Ok(content) => content,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        };
+        if hex::encode(Sha256::digest(&content)) != entry.object {
+            warn!(url = %entry.metadata.url, object = entry.object, "corrupt cache object, removing it");
+            let _lock = lock.write().await;
+            Self::remove_if_exists(&path).await?;
+            return Ok(None);
+        }
+        if let Some(index_entry) = self.index.lock().unwrap().get_mut(key) {
+            index_entry.accessed_at = now();
+            self.read_since_flush.store(true, Ordering::Relaxed);
+        }
+        Ok(Some(CachedPage {
+            content,
+            metadata: entry.metadata,
+        }))
+    }
+
+    async fn add(
+        &self,
+        key: &str,
+        content: &[u8],
+        metadata: &CacheMetadata,
+    ) -> std::io::Result<()> {
+        let object = hex::encode(Sha256::digest(content));
+        let object_path = self.object_path(&object);
+        {
+            let _lock = self.lock(&object).write().await;
+            if fs::metadata(&object_path).await.is_err() {
+                write_atomic(&object_path, content).await?;
+            }
+        }
+        let entry = Entry {
+            object,
+            size: content.len() as u64,
+            metadata: metadata.clone(),
+        };
+        self.write_entry(key, &entry).await
+    }
+
+    async fn update_metadata(&self, key: &str, metadata: &CacheMetadata) -> std::io::Result<()> {
+        let Some(mut entry) = self.read_entry(key).await? else {
+            return Err(ErrorKind::NotFound.into());
+        };
+        entry.metadata = metadata.clone();
+        self.write_entry(key, &entry).await
+    }
+
+    fn entries(&self) -> Vec<IndexEntry> {
+        self.index.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Compacts the index, which writes the access times along.
+    async fn flush(&self) -> std::io::Result<()> {
+        if !self.read_since_flush.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let _log = self.index_log.lock().await;
+        self.compact_index().await
+    }
+
+    async fn remove(&self, keys: &[String]) -> std::io::Result<Reclaimed> {
+        let mut reclaimed = Reclaimed::default();
+        if keys.is_empty() {
+            return Ok(reclaimed);
+        }
+        let _log = self.index_log.lock().await;
+        let mut candidates = HashMap::new();
+        for key in keys {
+            {
+                let _lock = self.lock(key).write().await;
+                Self::remove_if_exists(&self.entry_path(key)).await?;
+            }
+            if let Some(entry) = self.index.lock().unwrap().remove(key) {
+                candidates.insert(entry.object, entry.size);
+                reclaimed.entries += 1;
+            }
+        }
+        let referenced: HashSet<String> = self.entries().into_iter().map(|e| e.object).collect();
+        for (object, size) in candidates {
+            if referenced.contains(&object) {
+                continue;
+            }
+            let _lock = self.lock(&object).write().await;
+            Self::remove_if_exists(&self.object_path(&object)).await?;
+            reclaimed.objects += 1;
+            reclaimed.bytes += size;
+        }
+        self.compact_index().await?;
+        Ok(reclaimed)
+    }
+
+    /// Objects left behind by overwritten entries or interrupted writes.
+    async fn sweep(&self) -> std::io::Result<Reclaimed> {
+        let mut reclaimed = Reclaimed::default();
+        let referenced: HashSet<String> = self.entries().into_iter().map(|e| e.object).collect();
+        for path in self.sharded_files(Self::OBJECTS_DIR).await? {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if referenced.contains(&name) {
+                continue;
+            }
+            let _lock = self.lock(&name).write().await;
+            reclaimed.bytes += fs::metadata(&path).await?.len();
+            reclaimed.objects += 1;
+            Self::remove_if_exists(&path).await?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Checks every object against its hash and every entry against its object. With
+    /// `repair` broken files and temporary leftovers are deleted and the index is
+    /// rebuilt from the entries that remain.
+    async fn verify(&self, repair: bool) -> std::io::Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let is_tmp = |p: &PathBuf| p.to_string_lossy().ends_with(TMP_SUFFIX);
+
+        let mut objects = HashSet::new();
+        for path in self.sharded_files(Self::OBJECTS_DIR).await? {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let _lock = self.lock(&name).write().await;
+            if is_tmp(&path) {
+                report.temp_files += 1;
+            } else {
+                report.objects += 1;
+                if Self::is_hash(&name)
+                    && hex::encode(Sha256::digest(fs::read(&path).await?)) == name
+                {
+                    objects.insert(name);
+                    continue;
+                }
+                warn!(object = name, "corrupt cache object");
+                report.corrupt_objects += 1;
+            }
+            if repair {
+                Self::remove_if_exists(&path).await?;
+            }
+        }
+
+        let accessed: HashMap<String, u64> = self
+            .entries()
+            .into_iter()
+            .map(|e| (e.key, e.accessed_at))
+            .collect();
+        let mut index = HashMap::new();
+        for path in self.sharded_files(Self::ENTRIES_DIR).await? {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let _lock = self.lock(&name).write().await;
+            if is_tmp(&path) {
+                report.temp_files += 1;
+            } else {
+                report.entries += 1;
+                let key = name.trim_end_matches(".json").to_string();
+                let entry = match Self::is_hash(&key) {
+                    true => serde_json::from_slice::<Entry>(&fs::read(&path).await?).ok(),
+                    false => None,
+                };
+                if let Some(entry) = entry.filter(|e| objects.contains(&e.object)) {
+                    let accessed_at = accessed.get(&key).copied().unwrap_or_default();
+                    index.insert(
+                        key.clone(),
+                        IndexEntry {
+                            url: entry.metadata.url,
+                            key,
+                            object: entry.object,
+                            fetched_at: entry.metadata.fetched_at,
+                            size: entry.size,
+                            accessed_at,
+                        },
+                    );
+                    continue;
+                }
+                warn!(key, "broken cache entry");
+                report.broken_entries += 1;
+            }
+            if repair {
+                Self::remove_if_exists(&path).await?;
+            }
+        }
+
+        let referenced: HashSet<&String> = index.values().map(|e| &e.object).collect();
+        report.unreferenced_objects = objects.iter().filter(|o| !referenced.contains(o)).count();
+        if repair {
+            let _log = self.index_log.lock().await;
+            *self.index.lock().unwrap() = index;
+            self.compact_index().await?;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc};
+
+    use reqwest::Url;
+    use test::Bencher;
+    use tokio::{runtime::Runtime, sync::Mutex};
+
+    use super::FsCache;
+    use crate::cache::{
+        key,
+        test::{add_page, add_pages, temp_dir},
+        CacheBackend,
+    };
+
+    const BENCH_PAGES: usize = 256;
+
+    #[tokio::test]
+    async fn test_dedup_and_legacy_migration() {
+        let dir = temp_dir("cache");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let legacy = Url::parse("https://worm.fandom.com/wiki/Legacy").unwrap();
+        let legacy_name = urlencoding::encode(legacy.as_str()).to_string();
+        tokio::fs::write(dir.join(&legacy_name), b"old")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join(format!("{legacy_name}.meta.json")), b"{\"url")
+            .await
+            .unwrap();
+
+        let cache = FsCache::open(dir.clone()).await.unwrap();
+        assert_eq!(
+            cache.get(&key(&legacy)).await.unwrap().unwrap().content,
+            b"old"
+        );
+
+        for page in ["A", "B"] {
+            add_page(&cache, page, b"same body").await;
+        }
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.objects, 2);
+
+        // the index survives a reopen
+        let reopened = FsCache::open(dir.clone()).await.unwrap();
+        assert_eq!(reopened.entries().len(), 3);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_entries_are_misses_and_repaired() {
+        let dir = temp_dir("verify");
+        let cache = FsCache::open(dir.clone()).await.unwrap();
+        let urls = add_pages(&cache, &["A", "B", "C"]).await;
+        let object = cache
+            .read_entry(&key(&urls[0]))
+            .await
+            .unwrap()
+            .unwrap()
+            .object;
+        tokio::fs::write(cache.object_path(&object), b"bit rot")
+            .await
+            .unwrap();
+        tokio::fs::write(cache.entry_path(&key(&urls[1])), b"{\"obj")
+            .await
+            .unwrap();
+        tokio::fs::write(cache.object_path(&format!("{object}.1234.tmp")), b"half")
+            .await
+            .unwrap();
+        // stray files whose names are too short to shard or not hashes at all
+        for (sub, name) in [
+            (FsCache::OBJECTS_DIR, "x"),
+            (FsCache::ENTRIES_DIR, "€.json"),
+        ] {
+            let shard = dir.join(sub).join("ab");
+            tokio::fs::create_dir_all(&shard).await.unwrap();
+            tokio::fs::write(shard.join(name), b"stray").await.unwrap();
+        }
+
+        let report = cache.verify(false).await.unwrap();
+        assert_eq!(report.corrupt_objects, 2);
+        assert_eq!(report.broken_entries, 3);
+        assert_eq!(report.temp_files, 1);
+        assert_eq!(report.unreferenced_objects, 1);
+
+        assert!(cache.get(&key(&urls[0])).await.unwrap().is_none());
+        assert!(cache.get(&key(&urls[1])).await.unwrap().is_none());
+        assert_eq!(
+            cache.get(&key(&urls[2])).await.unwrap().unwrap().content,
+            b"C"
+        );
+
+        cache.verify(true).await.unwrap();
+        assert_eq!(cache.stats().entries, 1);
+        let report = cache.verify(false).await.unwrap();
+        assert_eq!(
+            report.broken_entries + report.corrupt_objects + report.temp_files,
+            0
+        );
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_access_times_survive_reopen() {
+        let dir = temp_dir("access");
+        let cache = FsCache::open(dir.clone()).await.unwrap();
+        let urls = add_pages(&cache, &["A", "B"]).await;
+        for entry in cache.index.lock().unwrap().values_mut() {
+            entry.accessed_at = 1;
+        }
+        cache.get(&key(&urls[0])).await.unwrap().unwrap();
+        cache.flush().await.unwrap();
+
+        let reopened = FsCache::open(dir.clone()).await.unwrap();
+        let accessed = |url| {
+            let index = reopened.index.lock().unwrap();
+            index.get(&key(url)).unwrap().accessed_at
+        };
+        assert!(accessed(&urls[0]) > 1);
+        assert_eq!(accessed(&urls[1]), 1);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    fn bench_cache(name: &str) -> (Runtime, PathBuf, Arc<FsCache>, Vec<Url>) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let dir = temp_dir(name);
+        let (cache, urls) = runtime.block_on(async {
+            let cache = FsCache::open(dir.clone()).await.unwrap();
+            let mut urls = Vec::new();
+            for i in 0..BENCH_PAGES {
+                let body = format!("{i}").repeat(16 * 1024);
+                urls.push(add_page(&cache, &format!("Page_{i}"), body.as_bytes()).await);
+            }
+            (cache, urls)
+        });
+        (runtime, dir, Arc::new(cache), urls)
+    }
+
+    /// What `Requester` did before, every lookup behind one cache wide lock.
+    #[bench]
+    fn bench_cached_reads_global_lock(b: &mut Bencher) {
+        let (runtime, dir, cache, urls) = bench_cache("bench-global-lock");
+        let cache = Arc::new(Mutex::new(cache));
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks: Vec<_> = urls
+                    .iter()
+                    .cloned()
+                    .map(|url| {
+                        let cache = cache.clone();
+                        tokio::spawn(async move {
+                            let c = cache.lock().await;
+                            c.get(&key(&url)).await.unwrap().unwrap().content.len()
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[bench]
+    fn bench_cached_reads_concurrent(b: &mut Bencher) {
+        let (runtime, dir, cache, urls) = bench_cache("bench-concurrent");
+        b.iter(|| {
+            runtime.block_on(async {
+                let tasks: Vec<_> = urls
+                    .iter()
+                    .cloned()
+                    .map(|url| {
+                        let cache = cache.clone();
+                        tokio::spawn(async move {
+                            cache.get(&key(&url)).await.unwrap().unwrap().content.len()
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
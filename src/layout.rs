@@ -1,8 +1,8 @@
-use std::{fmt::Debug, future::Future, sync::Arc};
+use std::{fmt::Debug, future::Future, sync::Arc, time::Instant};
 
 use tracing::warn;
 
-use crate::requester::SimpleRequest;
+use crate::{error::ParseError, metrics::LATENCIES, requester::SimpleRequest};
 
 pub trait LayoutComponent<Content, Extracted>: Debug {
     fn matches(&self, content: &Content) -> bool;
@@ -18,10 +18,8 @@ pub struct Layout<Content, Extracted> {
 
 impl<Content, Extracted> Layout<Content, Extracted> {
     fn name(&self) -> String {
-        self.components
-            .iter()
-            .flat_map(|c| [c.name(), ", ".into()])
-            .collect()
+        let names: Vec<String> = self.components.iter().map(|c| c.name()).collect();
+        names.join(", ")
     }
 }
 
@@ -48,13 +46,14 @@ impl<Content, Extracted> LayoutParser<Content, Extracted> {
         page: &str,
         parser: F,
         router: Router,
-    ) -> Vec<SimpleRequest>
+    ) -> Result<Vec<SimpleRequest>, ParseError>
     where
         F: Fn(&str) -> Content,
         Router: FnOnce(Vec<Extracted>) -> Fut,
         Fut: Future<Output = Vec<SimpleRequest>>,
     {
         let extracted = {
+            let started = Instant::now();
             let content = parser(page);
             let matching: Vec<_> = self
                 .layouts
@@ -62,20 +61,19 @@ impl<Content, Extracted> LayoutParser<Content, Extracted> {
                 .filter(|l| l.matches(&content))
                 .collect();
             if matching.len() > 1 {
-                let components_names: String = matching
-                    .iter()
-                    .flat_map(|l| [l.name(), "\n".into()])
-                    .collect();
+                let components_names: Vec<String> = matching.iter().map(|l| l.name()).collect();
                 warn!(request = ?request, layout = ?components_names,  "too many matching layouts");
-                panic!("too many matching layouts for {request:?}, matching layouts: {components_names}");
+                return Err(ParseError::TooManyMatchingLayouts(components_names));
             }
             if matching.len() == 0 {
                 warn!(request = ?request, "No matching layout");
-                panic!("No matching layout for {request:?}");
+                return Err(ParseError::NoMatchingLayout);
             }
             let layout = matching[0];
-            layout.extract(request, &content)
+            let extracted = layout.extract(request, &content);
+            LATENCIES.parse(&layout.name()).observe(started.elapsed());
+            extracted
         };
-        router(extracted).await
+        Ok(router(extracted).await)
     }
 }
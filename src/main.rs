@@ -1,33 +1,325 @@
 #![feature(return_position_impl_trait_in_trait)]
 #![feature(async_closure)]
 #![feature(async_fn_in_trait)]
+#![cfg_attr(test, feature(test))]
+#[cfg(test)]
+extern crate test;
 
-use std::fs::File;
+use std::{fs::File, future::pending, path::PathBuf, process::ExitCode, time::Duration};
 
+use cache::CacheBackend;
+use clap::{Parser as _, Subcommand};
+use config::{CacheBackendKind, Config, ParserKind};
+use fs_cache::FsCache;
+use memory_cache::MemoryCache;
+use requester::Requester;
+use spider::{CrawlOptions, PrefixRequestFilter, Spider};
+use store_cache::StoreCache;
+use tokio::{
+    select,
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
+use tracing::{info, warn};
 use tracing_subscriber::{filter::FilterFn, prelude::*};
-use worm_wiki::{WormRequestFilter, WormWikiListOfCharacters};
+use worm_wiki::WormWikiListOfCharacters;
 
+mod cache;
+mod canonical;
+mod codec;
+mod config;
+mod error;
+mod fingerprint;
+mod frontier;
+mod fs_cache;
 mod html;
 mod layout;
+mod memory_cache;
+mod metrics;
 mod parser;
+mod rate_limit;
 mod requester;
+mod retry;
+mod robots;
 mod spider;
+mod stats;
+mod store_cache;
 mod worm_wiki;
 
+#[derive(clap::Parser)]
+struct Cli {
+    /// TOML crawl description, the worm wiki crawl is used if omitted
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start a new crawl from the seeds, discarding any checkpoint
+    Crawl,
+    /// Continue the crawl from the last checkpoint in the state directory
+    Resume,
+    /// Run the parser over the cached pages again without network access, pages missing
+    /// from the cache are reported instead of fetched
+    Replay,
+    /// Inspect the page cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Number of cached pages and their size on disk
+    Stats,
+    /// Cached urls with fetch time and size
+    List {
+        /// only list urls starting with this prefix
+        prefix: Option<String>,
+    },
+    /// Apply the eviction limits of the cache policy
+    Evict,
+    /// Delete cached pages the last crawl did not reach
+    Gc,
+    /// Convert entries stored with another codec to the configured compression
+    Recompress,
+    /// Check cached bodies against their hashes and remove broken entries
+    Verify {
+        /// only report problems, change nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
 #[tokio::main]
-async fn main() {
-    setup_logging();
-    spider::Spider::run(
-        worm_wiki::initial(),
-        WormWikiListOfCharacters::new("characters".into()),
-        WormRequestFilter,
-        "page_cache".into(),
-    )
-    .await
-}
-
-fn setup_logging() {
-    let file = File::create("log.jsonl").unwrap();
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match cli.config.as_ref().map(Config::load) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+        None => Config::default(),
+    };
+    if let Err(e) = setup_logging(&config.log_file) {
+        eprintln!(
+            "could not create log file {}: {e}",
+            config.log_file.display()
+        );
+        return ExitCode::FAILURE;
+    }
+    let cache = match open_cache(&config).await {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("could not open cache: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match cli.command {
+        Command::Crawl => crawl(&config, cache, config.state_dir.clone(), false, false).await,
+        Command::Resume => crawl(&config, cache, config.state_dir.clone(), true, false).await,
+        Command::Replay => {
+            crawl(&config, cache, config.state_dir.join("replay"), false, true).await
+        }
+        Command::Cache {
+            command: CacheCommand::Stats,
+        } => {
+            let stats = cache.stats();
+            println!(
+                "{} cached pages, {} distinct bodies, {} bytes",
+                stats.entries, stats.objects, stats.bytes
+            );
+            ExitCode::SUCCESS
+        }
+        Command::Cache {
+            command: CacheCommand::List { prefix },
+        } => {
+            let mut entries = cache.entries();
+            entries.retain(|e| {
+                prefix
+                    .as_ref()
+                    .map_or(true, |p| e.url.as_str().starts_with(p))
+            });
+            entries.sort_by(|a, b| a.url.cmp(&b.url));
+            for entry in entries {
+                println!("{}\t{}\t{}", entry.url, entry.fetched_at, entry.size);
+            }
+            ExitCode::SUCCESS
+        }
+        Command::Cache {
+            command: CacheCommand::Evict,
+        } => match cache.evict(&config.cache_policy().eviction).await {
+            Ok(reclaimed) => {
+                print!("{reclaimed}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("could not evict cache entries: {e}");
+                ExitCode::FAILURE
+            }
+        },
+        Command::Cache {
+            command: CacheCommand::Recompress,
+        } => match cache.recompress(&config.cache_policy().compression).await {
+            Ok(recompressed) => {
+                print!("{recompressed}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("could not recompress cache: {e}");
+                ExitCode::FAILURE
+            }
+        },
+        Command::Cache {
+            command: CacheCommand::Gc,
+        } => {
+            let Some(seen) = Spider::last_seen(&config.state_dir, config.fingerprinter()).await
+            else {
+                eprintln!("no crawl checkpoint in {}", config.state_dir.display());
+                return ExitCode::FAILURE;
+            };
+            match cache.gc(&seen).await {
+                Ok(reclaimed) => {
+                    print!("{reclaimed}");
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("could not collect cache garbage: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Command::Cache {
+            command: CacheCommand::Verify { dry_run },
+        } => match cache.verify(!dry_run).await {
+            Ok(report) => {
+                print!("{report}");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("could not verify cache: {e}");
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+async fn crawl(
+    config: &Config,
+    cache: Box<dyn CacheBackend>,
+    state_dir: PathBuf,
+    resume: bool,
+    offline: bool,
+) -> ExitCode {
+    // a replay must not lose the pages it is about to replay
+    if !offline {
+        match cache.evict(&config.cache_policy().eviction).await {
+            Ok(evicted) if evicted.entries > 0 => info!(
+                entries = evicted.entries,
+                bytes = evicted.bytes,
+                "evicted cache entries"
+            ),
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("could not evict cache entries: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let requester = Requester::new(
+        cache,
+        config.cache_policy(),
+        config.retry_policy(),
+        config.rate_limits(),
+        config.fingerprinter(),
+        offline,
+    );
+    if let Some(addr) = config.metrics_addr {
+        if let Err(e) = metrics::serve(addr, requester.counters().clone()).await {
+            eprintln!("could not serve metrics on {addr}: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let filter = PrefixRequestFilter {
+        prefixes: config.allowed_prefixes.clone(),
+    };
+    let report = match config.parser {
+        ParserKind::WormWiki => {
+            Spider::run(
+                config.initial_requests(),
+                WormWikiListOfCharacters::new(config.output_dir.clone()),
+                filter,
+                requester,
+                CrawlOptions {
+                    state_dir,
+                    resume,
+                    frontier: config.frontier,
+                    max_depth: config.max_depth,
+                    canonicalizer: config.canonical.clone(),
+                    max_tasks: config.max_tasks,
+                    max_buffered_bytes: Some(config.max_buffered_bytes).filter(|m| *m > 0),
+                    shutdown: shutdown_signals(),
+                    shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs),
+                },
+            )
+            .await
+        }
+    };
+    print!("{report}");
+    if report.interrupted {
+        // what shells report for a command stopped by ctrl-c
+        return ExitCode::from(130);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Sends on every SIGINT and SIGTERM, never if the handlers cannot be installed.
+fn shutdown_signals() -> UnboundedReceiver<()> {
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!(error = %e, "could not listen for SIGTERM");
+                return pending().await;
+            }
+        };
+        loop {
+            select! {
+                result = ctrl_c() => {
+                    if let Err(e) = result {
+                        warn!(error = %e, "could not listen for SIGINT");
+                        return pending().await;
+                    }
+                }
+                _ = terminate.recv() => {}
+            }
+            if sender.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+async fn open_cache(config: &Config) -> std::io::Result<Box<dyn CacheBackend>> {
+    let dir = config.cache_dir.clone();
+    Ok(match config.cache_backend {
+        CacheBackendKind::Fs => Box::new(FsCache::open(dir).await?),
+        CacheBackendKind::Store => Box::new(StoreCache::open(dir).await?),
+        CacheBackendKind::Memory => Box::new(MemoryCache::new()),
+    })
+}
+
+fn setup_logging(path: &PathBuf) -> std::io::Result<()> {
+    let file = File::create(path)?;
     let json_layer = tracing_subscriber::fmt::layer()
         .json()
         .with_writer(file)
@@ -38,4 +330,5 @@ fn setup_logging() {
             !(html5ever_path || html5ever_target || selector_target)
         }));
     tracing_subscriber::registry().with(json_layer).init();
+    Ok(())
 }
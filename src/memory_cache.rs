@@ -0,0 +1,124 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+
+use crate::cache::{
+    now, CacheBackend, CacheMetadata, CachedPage, IndexEntry, Reclaimed, VerifyReport,
+};
+
+/// Keeps everything in memory, for tests and throwaway crawls.
+#[derive(Default)]
+pub struct MemoryCache {
+    pages: Mutex<HashMap<String, Stored>>,
+}
+
+struct Stored {
+    entry: IndexEntry,
+    metadata: CacheMetadata,
+    content: Vec<u8>,
+}
+
+impl MemoryCache {
+    pub fn new() -> MemoryCache {
+        MemoryCache::default()
+    }
+}
+
+#[async_trait]
+impl CacheBackend for MemoryCache {
+    async fn get(&self, key: &str) -> std::io::Result<Option<CachedPage>> {
+        let mut pages = self.pages.lock().unwrap();
+        Ok(pages.get_mut(key).map(|stored| {
+            stored.entry.accessed_at = now();
+            CachedPage {
+                content: stored.content.clone(),
+                metadata: stored.metadata.clone(),
+            }
+        }))
+    }
+
+    async fn add(
+        &self,
+        key: &str,
+        content: &[u8],
+        metadata: &CacheMetadata,
+    ) -> std::io::Result<()> {
+        let entry = IndexEntry {
+            url: metadata.url.clone(),
+            key: key.to_string(),
+            object: hex::encode(Sha256::digest(content)),
+            fetched_at: metadata.fetched_at,
+            size: content.len() as u64,
+            accessed_at: now(),
+        };
+        let stored = Stored {
+            entry,
+            metadata: metadata.clone(),
+            content: content.to_vec(),
+        };
+        self.pages.lock().unwrap().insert(key.to_string(), stored);
+        Ok(())
+    }
+
+    async fn update_metadata(&self, key: &str, metadata: &CacheMetadata) -> std::io::Result<()> {
+        let mut pages = self.pages.lock().unwrap();
+        let Some(stored) = pages.get_mut(key) else {
+            return Err(ErrorKind::NotFound.into());
+        };
+        stored.entry.fetched_at = metadata.fetched_at;
+        stored.metadata = metadata.clone();
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<IndexEntry> {
+        let pages = self.pages.lock().unwrap();
+        pages.values().map(|s| s.entry.clone()).collect()
+    }
+
+    async fn remove(&self, keys: &[String]) -> std::io::Result<Reclaimed> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut reclaimed = Reclaimed::default();
+        let mut candidates = HashMap::new();
+        for key in keys {
+            if let Some(stored) = pages.remove(key) {
+                candidates.insert(stored.entry.object, stored.entry.size);
+                reclaimed.entries += 1;
+            }
+        }
+        let referenced: HashSet<&String> = pages.values().map(|s| &s.entry.object).collect();
+        for (object, size) in candidates {
+            if !referenced.contains(&object) {
+                reclaimed.objects += 1;
+                reclaimed.bytes += size;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    async fn verify(&self, repair: bool) -> std::io::Result<VerifyReport> {
+        let mut pages = self.pages.lock().unwrap();
+        let mut report = VerifyReport {
+            entries: pages.len(),
+            objects: pages.len(),
+            ..VerifyReport::default()
+        };
+        let broken: Vec<String> = pages
+            .iter()
+            .filter(|(_, s)| hex::encode(Sha256::digest(&s.content)) != s.entry.object)
+            .map(|(key, _)| key.clone())
+            .collect();
+        report.broken_entries = broken.len();
+        report.corrupt_objects = broken.len();
+        if repair {
+            for key in broken {
+                pages.remove(&key);
+            }
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,283 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::stats::Counters;
+
+/// Upper bounds in seconds, from a cached page to a slow fetch.
+const BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Latencies of the whole process, the parser records into it without a handle to the
+/// crawl.
+pub static LATENCIES: Lazy<Latencies> = Lazy::new(Latencies::default);
+
+#[derive(Default)]
+pub struct Latencies {
+    /// one attempt over the network, without waiting for the rate limiter
+    pub fetch: Histogram,
+    /// reading and decompressing a cached page
+    pub cache_lookup: Histogram,
+    /// by layout name, pages without a matching layout are not recorded
+    parse: Mutex<BTreeMap<String, Arc<Histogram>>>,
+}
+
+impl Latencies {
+    pub fn parse(&self, layout: &str) -> Arc<Histogram> {
+        self.parse
+            .lock()
+            .unwrap()
+            .entry(layout.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Buckets are stored individually and summed up here, as the format wants them
+    /// cumulative.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels}le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {count}");
+        let labels = labels.trim_end_matches(',');
+        let braces = |l: &str| {
+            if l.is_empty() {
+                String::new()
+            } else {
+                format!("{{{l}}}")
+            }
+        };
+        let _ = writeln!(out, "{name}_sum{} {sum}", braces(labels));
+        let _ = writeln!(out, "{name}_count{} {count}", braces(labels));
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The Prometheus text format of the crawl counters and the latencies.
+pub fn render(counters: &Counters, latencies: &Latencies) -> String {
+    let mut out = String::new();
+    let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    let gauges = [
+        (
+            "atrico_queued",
+            "Requests waiting in the frontier.",
+            &counters.queued,
+        ),
+        (
+            "atrico_in_flight",
+            "Requests being fetched or parsed.",
+            &counters.in_flight,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        header(&mut out, name, "gauge", help);
+        let _ = writeln!(out, "{name} {}", get(value));
+    }
+    let totals = [
+        (
+            "atrico_fetched_total",
+            "Responses received from the network.",
+            &counters.fetched,
+        ),
+        (
+            "atrico_fetched_bytes_total",
+            "Body bytes received from the network.",
+            &counters.bytes,
+        ),
+        (
+            "atrico_cache_hits_total",
+            "Pages served from the cache.",
+            &counters.cache_hits,
+        ),
+        (
+            "atrico_cache_misses_total",
+            "Pages missing or stale in the cache.",
+            &counters.cache_misses,
+        ),
+        (
+            "atrico_filtered_total",
+            "Discovered requests dropped by depth, filter or robots.txt.",
+            &counters.filtered,
+        ),
+        (
+            "atrico_parse_failures_total",
+            "Pages the parser failed on.",
+            &counters.parse_failures,
+        ),
+    ];
+    for (name, help, value) in totals {
+        header(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{name} {}", get(value));
+    }
+    header(
+        &mut out,
+        "atrico_pages_total",
+        "counter",
+        "Crawled pages by host.",
+    );
+    for (host, pages) in counters.hosts() {
+        let _ = writeln!(
+            out,
+            "atrico_pages_total{{host=\"{}\"}} {pages}",
+            escape(&host)
+        );
+    }
+
+    header(
+        &mut out,
+        "atrico_fetch_seconds",
+        "histogram",
+        "Time of one fetch attempt.",
+    );
+    latencies.fetch.render(&mut out, "atrico_fetch_seconds", "");
+    header(
+        &mut out,
+        "atrico_cache_lookup_seconds",
+        "histogram",
+        "Time to read a page from the cache.",
+    );
+    latencies
+        .cache_lookup
+        .render(&mut out, "atrico_cache_lookup_seconds", "");
+    header(
+        &mut out,
+        "atrico_parse_seconds",
+        "histogram",
+        "Time to parse a page by layout.",
+    );
+    for (layout, histogram) in latencies.parse.lock().unwrap().iter() {
+        let labels = format!("layout=\"{}\",", escape(layout));
+        histogram.render(&mut out, "atrico_parse_seconds", &labels);
+    }
+    out
+}
+
+/// Answers `GET /metrics` on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr, counters: Arc<Counters>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(addr = %listener.local_addr()?, "serving metrics");
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(respond(stream, counters.clone()));
+                }
+                Err(e) => warn!(error = %e, "failed to accept metrics connection"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// One response per connection, scrapers do not need keep-alive.
+async fn respond(stream: TcpStream, counters: Arc<Counters>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let Ok(Some(request_line)) = lines.next_line().await else {
+        return;
+    };
+    while let Ok(Some(header)) = lines.next_line().await {
+        if header.is_empty() {
+            break;
+        }
+    }
+    let path = request_line.split(' ').nth(1).unwrap_or_default();
+    let (status, body) = if request_line.starts_with("GET ") && path == "/metrics" {
+        ("200 OK", render(&counters, &LATENCIES))
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = write.write_all(response.as_bytes()).await;
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use reqwest::Url;
+
+    use super::{render, Latencies};
+    use crate::stats::{self, Counters};
+
+    #[test]
+    fn test_render() {
+        let counters = Counters::default();
+        stats::inc(&counters.fetched, 3);
+        counters.crawled(&Url::parse("https://worm.fandom.com/wiki/Skitter").unwrap());
+        let latencies = Latencies::default();
+        latencies.fetch.observe(Duration::from_millis(30));
+        latencies.fetch.observe(Duration::from_secs(60));
+        latencies
+            .parse("ArticleLinksComponent, \"Sheet\"")
+            .observe(Duration::from_millis(2));
+
+        let text = render(&counters, &latencies);
+        for line in [
+            "# TYPE atrico_fetched_total counter",
+            "atrico_fetched_total 3",
+            "atrico_pages_total{host=\"worm.fandom.com\"} 1",
+            "atrico_fetch_seconds_bucket{le=\"0.025\"} 0",
+            "atrico_fetch_seconds_bucket{le=\"0.05\"} 1",
+            "atrico_fetch_seconds_bucket{le=\"+Inf\"} 2",
+            "atrico_fetch_seconds_sum 60.03",
+            "atrico_fetch_seconds_count 2",
+            "atrico_parse_seconds_bucket{layout=\"ArticleLinksComponent, \\\"Sheet\\\"\",le=\"0.0025\"} 1",
+            "atrico_parse_seconds_count{layout=\"ArticleLinksComponent, \\\"Sheet\\\"\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing from\n{text}");
+        }
+    }
+}
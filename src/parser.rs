@@ -1,11 +1,20 @@
 use std::future::Future;
 
-use crate::requester::SimpleRequest;
+use crate::{
+    error::ParseError,
+    requester::{Page, SimpleRequest},
+};
 
 pub trait Parser: Send + 'static {
     fn parse<'a>(
         self,
         request: &'a SimpleRequest,
-        page: &'a str,
-    ) -> impl Future<Output = Vec<SimpleRequest>> + Send + 'a;
+        page: &'a Page,
+    ) -> impl Future<Output = Result<Vec<SimpleRequest>, ParseError>> + Send + 'a;
+
+    /// How early a discovered request is crawled with `FrontierKind::Priority`, higher
+    /// is earlier.
+    fn priority(&self, _request: &SimpleRequest) -> i64 {
+        0
+    }
 }
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::sleep,
+};
+
+/// The slowest a host is ever limited to, one request per interval
+pub const MAX_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Debug)]
+pub struct HostLimits {
+    /// None disables the token bucket for the host
+    pub requests_per_second: Option<f64>,
+    pub burst: u32,
+    pub max_in_flight: usize,
+}
+
+impl Default for HostLimits {
+    fn default() -> Self {
+        HostLimits {
+            requests_per_second: Some(1.0),
+            burst: 1,
+            max_in_flight: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimits {
+    pub max_in_flight: usize,
+    pub default_host: HostLimits,
+    pub hosts: HashMap<String, HostLimits>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            max_in_flight: 16,
+            default_host: HostLimits::default(),
+            hosts: HashMap::new(),
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: u32, now: Instant) -> TokenBucket {
+        let capacity = burst.max(1) as f64;
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Takes a token and returns how long the caller has to wait before using it.
+    /// Tokens may go negative, which queues callers in the order they reserved.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+struct Host {
+    bucket: Mutex<Option<TokenBucket>>,
+    in_flight: Arc<Semaphore>,
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    global: Arc<Semaphore>,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+pub struct Permit {
+    _host: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            global: Arc::new(Semaphore::new(limits.max_in_flight.max(1))),
+            limits,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn host(&self, url: &Url) -> Arc<Host> {
+        let name = url.host_str().unwrap_or_default().to_string();
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(name)
+            .or_insert_with_key(|name| {
+                let limits = self
+                    .limits
+                    .hosts
+                    .get(name)
+                    .unwrap_or(&self.limits.default_host);
+                Arc::new(Host {
+                    bucket: Mutex::new(
+                        limits
+                            .requests_per_second
+                            .map(|rate| TokenBucket::new(rate, limits.burst, Instant::now())),
+                    ),
+                    in_flight: Arc::new(Semaphore::new(limits.max_in_flight.max(1))),
+                })
+            })
+            .clone()
+    }
+
+    /// Slows the host of `url` down to at most one request per `delay`, configured
+    /// limits that are already stricter are kept.
+    pub fn set_crawl_delay(&self, url: &Url, delay: Duration) {
+        if delay.is_zero() {
+            return;
+        }
+        let rate = 1.0 / delay.as_secs_f64();
+        let host = self.host(url);
+        let mut bucket = host.bucket.lock().unwrap();
+        match bucket.as_mut() {
+            Some(b) if b.rate <= rate => {}
+            Some(b) => {
+                b.rate = rate;
+                b.capacity = 1.0;
+                b.tokens = b.tokens.min(1.0);
+            }
+            None => *bucket = Some(TokenBucket::new(rate, 1, Instant::now())),
+        }
+    }
+
+    /// Waits until a request to `url` is allowed, the returned permit has to be held
+    /// until the request finished.
+    pub async fn acquire(&self, url: &Url) -> Permit {
+        let host = self.host(url);
+        let host_permit = host.in_flight.clone().acquire_owned().await.unwrap();
+        let wait = host
+            .bucket
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.reserve(Instant::now()));
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+        let global_permit = self.global.clone().acquire_owned().await.unwrap();
+        Permit {
+            _host: host_permit,
+            _global: global_permit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::TokenBucket;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2, start);
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.reserve(start), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(start), Duration::from_millis(1000));
+        // refills at 2 tokens per second but never above the burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(later), Duration::ZERO);
+        assert_eq!(bucket.reserve(later), Duration::ZERO);
+        assert_eq!(bucket.reserve(later), Duration::from_millis(500));
+    }
+}
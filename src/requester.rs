@@ -1,19 +1,38 @@
-use std::time::Duration;
-use std::{path::PathBuf, sync::Arc};
-
-use flate2::read::ZlibDecoder;
-use flate2::write::ZlibEncoder;
-use flate2::Compression;
-use reqwest::header::HeaderMap;
-use reqwest::{Body, Client, Method};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use reqwest::{Body, Client, Method, StatusCode};
 use reqwest::{Request, Url};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug)]
+use crate::cache::{CacheBackend, CacheMetadata, CachePolicy};
+use crate::error::CrawlError;
+use crate::fingerprint::Fingerprinter;
+use crate::metrics::LATENCIES;
+use crate::rate_limit::{Permit, RateLimiter, RateLimits};
+use crate::retry::{self, RetryPolicy};
+use crate::robots::Robots;
+use crate::stats::{self, Counters};
+
+/// The product token robots.txt groups have to name to apply to us.
+pub const ROBOTS_AGENT: &str = "atrico";
+/// Sent with every request, starts with `ROBOTS_AGENT` so the groups we follow are the
+/// ones that apply to what sites see.
+pub const USER_AGENT: &str = "atrico/0.1";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "StoredRequest", into = "StoredRequest")]
 pub struct SimpleRequest {
     pub method: Method,
     pub url: Url,
     pub headers: HeaderMap,
     pub body: Option<String>,
+    /// links followed from a seed, set by the spider
+    pub depth: u32,
+    /// the page this request was found on, set by the spider
+    pub referrer: Option<Url>,
 }
 
 impl From<SimpleRequest> for Request {
@@ -25,130 +44,313 @@ impl From<SimpleRequest> for Request {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct StoredRequest {
+    method: String,
+    url: Url,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    #[serde(default)]
+    depth: u32,
+    #[serde(default)]
+    referrer: Option<Url>,
+}
+
+impl From<SimpleRequest> for StoredRequest {
+    fn from(value: SimpleRequest) -> Self {
+        StoredRequest {
+            method: value.method.to_string(),
+            url: value.url,
+            headers: header_pairs(&value.headers),
+            body: value.body,
+            depth: value.depth,
+            referrer: value.referrer,
+        }
+    }
+}
+
+impl TryFrom<StoredRequest> for SimpleRequest {
+    type Error = String;
+
+    fn try_from(value: StoredRequest) -> Result<Self, Self::Error> {
+        let method = Method::from_bytes(value.method.as_bytes()).map_err(|e| e.to_string())?;
+        Ok(SimpleRequest {
+            method,
+            url: value.url,
+            headers: pairs_to_headers(value.headers)?,
+            body: value.body,
+            depth: value.depth,
+            referrer: value.referrer,
+        })
+    }
+}
+
+pub fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .flat_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+        .collect()
+}
+
+pub fn pairs_to_headers(pairs: Vec<(String, String)>) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for (k, v) in pairs {
+        headers.append(
+            HeaderName::try_from(k).map_err(|e| e.to_string())?,
+            HeaderValue::try_from(v).map_err(|e| e.to_string())?,
+        );
+    }
+    Ok(headers)
+}
+
+#[derive(Clone, Debug)]
+pub struct Page {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
 pub struct Requester {
-    cache: Mutex<PageCache>,
+    cache: Box<dyn CacheBackend>,
+    policy: CachePolicy,
     clients: ClientProvider,
+    retry: RetryPolicy,
+    fingerprinter: Fingerprinter,
+    robots: std::sync::Mutex<HashMap<String, Arc<OnceCell<Robots>>>>,
+    /// serve only from the cache, the network is never touched
+    offline: bool,
+    counters: Arc<Counters>,
 }
 
 impl Requester {
-    pub fn new(cache_dir: PathBuf) -> Requester {
+    pub fn new(
+        cache: Box<dyn CacheBackend>,
+        policy: CachePolicy,
+        retry: RetryPolicy,
+        limits: RateLimits,
+        fingerprinter: Fingerprinter,
+        offline: bool,
+    ) -> Requester {
         Requester {
-            cache: Mutex::new(PageCache {
-                base_dir: cache_dir,
-            }),
-            clients: ClientProvider::new(),
+            cache,
+            policy,
+            clients: ClientProvider::new(limits),
+            retry,
+            fingerprinter,
+            robots: std::sync::Mutex::new(HashMap::new()),
+            offline,
+            counters: Arc::default(),
         }
     }
-    pub async fn execute(self: Arc<Self>, r: SimpleRequest) -> String {
-        if let Some(r) = self.get_from_cache(&r.url).await {
-            r
-        } else {
-            let u = r.url.clone();
-            let client = self.clients.get_client().await;
-            let content = Self::e(&client, r).await;
-            self.clients.return_client(client);
-            let content = content.unwrap();
-            self.write_to_cache(&u, &content).await;
-            content
-        }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
     }
 
-    async fn e(client: &Client, r: SimpleRequest) -> Result<String, reqwest::Error> {
-        let response = client.execute(r.into()).await?;
-        let content = response.text().await?;
-        Ok(content)
+    pub fn fingerprinter(&self) -> &Fingerprinter {
+        &self.fingerprinter
     }
 
-    async fn get_from_cache(&self, url: &Url) -> Option<String> {
-        let c = self.cache.lock().await;
-        let compressed = c.get(url).await?;
-        drop(c);
-        let mut decoder = ZlibDecoder::new(compressed.as_slice());
+    pub fn counters(&self) -> &Arc<Counters> {
+        &self.counters
+    }
 
-        let mut decompressed = String::new();
-        decoder.read_to_string(&mut decompressed).unwrap();
-        Some(decompressed)
+    /// Persists the access times of cache hits, so LRU eviction sees them next run.
+    pub async fn flush_cache(&self) -> std::io::Result<()> {
+        self.cache.flush().await
     }
 
-    async fn write_to_cache(&self, url: &Url, content: &str) {
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(content.as_bytes()).unwrap();
-        let compressed = encoder.finish().unwrap();
-        let c = self.cache.lock().await;
-        c.add(url, &compressed).await;
+    pub async fn allowed(&self, url: &Url) -> bool {
+        let origin = url.origin();
+        // cached pages passed robots.txt when they were fetched
+        if !origin.is_tuple() || self.offline {
+            return true;
+        }
+        let cell = self
+            .robots
+            .lock()
+            .unwrap()
+            .entry(origin.ascii_serialization())
+            .or_default()
+            .clone();
+        cell.get_or_init(|| self.fetch_robots(url))
+            .await
+            .is_allowed(url)
     }
-}
 
-use std::io::prelude::*;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::spawn;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::sync::Mutex;
-use tokio::time::sleep;
+    async fn fetch_robots(&self, url: &Url) -> Robots {
+        let Ok(robots_url) = url.join("/robots.txt") else {
+            return Robots::allow_all();
+        };
+        let request = SimpleRequest {
+            method: Method::GET,
+            url: robots_url,
+            headers: HeaderMap::new(),
+            body: None,
+            depth: 0,
+            referrer: None,
+        };
+        let robots = match self.fetch(request).await {
+            Ok(page) => Robots::parse(&page.body, ROBOTS_AGENT),
+            Err(CrawlError::Status { status, .. }) if status.is_client_error() => {
+                Robots::allow_all()
+            }
+            Err(e) => {
+                warn!(url = %url, error = %e, "robots.txt unreachable, not crawling host");
+                Robots::disallow_all()
+            }
+        };
+        if let Some(delay) = robots.crawl_delay() {
+            self.clients.limiter.set_crawl_delay(url, delay);
+        }
+        robots
+    }
 
-struct PageCache {
-    base_dir: PathBuf,
-}
+    pub async fn execute(self: Arc<Self>, mut r: SimpleRequest) -> Result<Page, CrawlError> {
+        // taken before the validators are added below
+        let key = self.fingerprinter.fingerprint(&r);
+        let lookup = Instant::now();
+        let cached = self.get_from_cache(&key).await?;
+        LATENCIES.cache_lookup.observe(lookup.elapsed());
+        let stale = match cached {
+            Some((body, metadata)) => {
+                if self.offline || self.policy.is_fresh(&metadata) {
+                    stats::inc(&self.counters.cache_hits, 1);
+                    return Ok(Page {
+                        status: metadata.status(),
+                        headers: metadata.headers(),
+                        body,
+                    });
+                }
+                (self.policy.revalidate && metadata.can_revalidate()).then_some((body, metadata))
+            }
+            None if self.offline => {
+                stats::inc(&self.counters.cache_misses, 1);
+                return Err(CrawlError::NotCached);
+            }
+            None => None,
+        };
+        stats::inc(&self.counters.cache_misses, 1);
+        if let Some((_, metadata)) = stale.as_ref() {
+            let validators = [
+                (IF_NONE_MATCH, metadata.etag.as_ref()),
+                (IF_MODIFIED_SINCE, metadata.last_modified.as_ref()),
+            ];
+            for (name, value) in validators {
+                if let Some(value) = value.and_then(|v| HeaderValue::from_str(v).ok()) {
+                    r.headers.insert(name, value);
+                }
+            }
+        }
+
+        let u = r.url.clone();
+        // only successful responses reach this point, error pages are never cached
+        let page = self.fetch(r).await?;
+        stats::inc(&self.counters.fetched, 1);
+        stats::inc(&self.counters.bytes, page.body.len() as u64);
+        if page.status == StatusCode::NOT_MODIFIED {
+            let Some((body, mut metadata)) = stale else {
+                return Err(CrawlError::Status {
+                    status: page.status,
+                    retry_after: None,
+                });
+            };
+            metadata.refresh(&page.headers);
+            if let Err(e) = self.cache.update_metadata(&key, &metadata).await {
+                warn!(url = %u, error = ?e, "failed to update cache metadata");
+            }
+            return Ok(Page {
+                status: metadata.status(),
+                headers: metadata.headers(),
+                body,
+            });
+        }
+        if let Err(e) = self.write_to_cache(&key, &u, &page).await {
+            warn!(url = %u, error = ?e, "failed to write page to cache");
+        }
+        Ok(page)
+    }
+
+    async fn fetch(&self, r: SimpleRequest) -> Result<Page, CrawlError> {
+        let mut attempt = 1;
+        loop {
+            let (client, permit) = self.clients.get_client(&r.url).await;
+            let started = Instant::now();
+            let content = Self::e(&client, r.clone()).await;
+            LATENCIES.fetch.observe(started.elapsed());
+            drop(permit);
+            match content {
+                Err(e) if attempt < self.retry.max_attempts && self.retry.is_retryable(&e) => {
+                    let delay = self.retry.delay(attempt, &e);
+                    warn!(url = %r.url, attempt, delay = ?delay, error = %e, "retrying request");
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                content => return content,
+            }
+        }
+    }
 
-impl PageCache {
-    async fn add(&self, url: &Url, content: &[u8]) {
-        let path = self
-            .base_dir
-            .join(urlencoding::encode(url.as_str()).as_ref());
-        let mut file = File::create(path).await.unwrap();
-        file.write_all(&content).await.unwrap();
-        file.flush().await.unwrap();
+    async fn e(client: &Client, r: SimpleRequest) -> Result<Page, CrawlError> {
+        let response = client.execute(r.into()).await?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        if !status.is_success() && status != StatusCode::NOT_MODIFIED {
+            return Err(CrawlError::Status {
+                status,
+                retry_after: retry::retry_after(status, &headers),
+            });
+        }
+        let body = response.text().await?;
+        Ok(Page {
+            status,
+            headers,
+            body,
+        })
     }
 
-    async fn get(&self, url: &Url) -> Option<Vec<u8>> {
-        let path = self
-            .base_dir
-            .join(urlencoding::encode(url.as_str()).as_ref());
-        let mut file = File::open(path).await.ok()?;
-        let mut s = Vec::new();
-        file.read_to_end(&mut s).await.unwrap();
+    async fn get_from_cache(&self, key: &str) -> std::io::Result<Option<(String, CacheMetadata)>> {
+        let Some(cached) = self.cache.get(key).await? else {
+            return Ok(None);
+        };
+        let decompressed = cached.metadata.codec.decompress(&cached.content)?;
+        let body = String::from_utf8(decompressed)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(Some((body, cached.metadata)))
+    }
 
-        Some(s)
+    async fn write_to_cache(&self, key: &str, url: &Url, page: &Page) -> std::io::Result<()> {
+        let compression = &self.policy.compression;
+        let compressed = compression.compress(page.body.as_bytes())?;
+        let mut metadata = CacheMetadata::new(url, page.status, &page.headers);
+        metadata.codec = compression.codec;
+        self.cache.add(key, &compressed, &metadata).await
     }
 }
 
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
+use tracing::warn;
+
 struct ClientProvider {
-    all_clients: Mutex<UnboundedReceiver<Client>>,
-    returned_clients: UnboundedSender<Client>,
+    client: Client,
+    limiter: RateLimiter,
 }
 
 impl ClientProvider {
-    fn new() -> ClientProvider {
-        let (sender, reciever) = unbounded_channel();
-        sender
-            .send(
-                Client::builder()
-                    .cookie_store(true)
-                    .user_agent(
-                        "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/110.0",
-                    )
-                    .build()
-                    .unwrap(),
-            )
-            .unwrap();
+    fn new(limits: RateLimits) -> ClientProvider {
         ClientProvider {
-            all_clients: Mutex::new(reciever),
-            returned_clients: sender,
+            client: Client::builder()
+                .cookie_store(true)
+                .user_agent(USER_AGENT)
+                .build()
+                .unwrap(),
+            limiter: RateLimiter::new(limits),
         }
     }
 
-    async fn get_client(&self) -> Client {
-        let mut r = self.all_clients.lock().await;
-        r.recv().await.unwrap()
-    }
-
-    fn return_client(&self, c: Client) {
-        let r = self.returned_clients.clone();
-        spawn(async move {
-            sleep(Duration::from_millis(1000)).await;
-            r.send(c).unwrap()
-        });
+    async fn get_client(&self, url: &Url) -> (Client, Permit) {
+        let permit = self.limiter.acquire(url).await;
+        (self.client.clone(), permit)
     }
 }
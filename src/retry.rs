@@ -0,0 +1,150 @@
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+
+use crate::error::CrawlError;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// fraction of the backoff that is randomized, 0.0 disables jitter
+    pub jitter: f64,
+    pub retry_statuses: Vec<StatusCode>,
+    pub retry_timeouts: bool,
+    pub retry_connect_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.5,
+            retry_statuses: vec![
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            retry_timeouts: true,
+            retry_connect_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, error: &CrawlError) -> bool {
+        match error {
+            CrawlError::Request(e) => {
+                (self.retry_timeouts && e.is_timeout())
+                    || (self.retry_connect_errors && e.is_connect())
+                    || e.status().is_some_and(|s| self.retry_statuses.contains(&s))
+            }
+            CrawlError::Status { status, .. } => self.retry_statuses.contains(status),
+            _ => false,
+        }
+    }
+
+    /// Delay before the attempt following `attempt` (1 based), a server provided
+    /// `Retry-After` takes precedence over the exponential backoff.
+    pub fn delay(&self, attempt: u32, error: &CrawlError) -> Duration {
+        if let CrawlError::Status {
+            retry_after: Some(retry_after),
+            ..
+        } = error
+        {
+            return (*retry_after).min(self.max_delay);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        if self.jitter <= 0.0 {
+            return backoff;
+        }
+        let jitter = self.jitter.min(1.0);
+        backoff.mul_f64(1.0 - jitter * rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+pub fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use reqwest::{header::HeaderMap, StatusCode};
+
+    use super::{retry_after, RetryPolicy};
+    use crate::error::CrawlError;
+
+    fn status(status: StatusCode, retry_after: Option<Duration>) -> CrawlError {
+        CrawlError::Status {
+            status,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            max_delay: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let e = status(StatusCode::BAD_GATEWAY, None);
+        assert_eq!(policy.delay(1, &e), Duration::from_millis(500));
+        assert_eq!(policy.delay(2, &e), Duration::from_millis(1000));
+        assert_eq!(policy.delay(3, &e), Duration::from_millis(2000));
+        assert_eq!(policy.delay(4, &e), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_retry_after_takes_precedence() {
+        let policy = RetryPolicy::default();
+        let e = status(StatusCode::TOO_MANY_REQUESTS, Some(Duration::from_secs(7)));
+        assert_eq!(policy.delay(1, &e), Duration::from_secs(7));
+        assert!(policy.is_retryable(&e));
+        assert!(!policy.is_retryable(&status(StatusCode::NOT_FOUND, None)));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "120".parse().unwrap());
+        assert_eq!(
+            retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(retry_after(StatusCode::BAD_GATEWAY, &headers), None);
+        headers.insert(
+            "retry-after",
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(
+            retry_after(StatusCode::TOO_MANY_REQUESTS, &headers),
+            Some(Duration::ZERO)
+        );
+    }
+}
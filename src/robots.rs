@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+use reqwest::Url;
+
+use crate::rate_limit::MAX_INTERVAL;
+
+#[derive(Debug, Default)]
+pub struct Robots {
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+#[derive(Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    pub fn allow_all() -> Robots {
+        Robots::default()
+    }
+
+    pub fn disallow_all() -> Robots {
+        Robots {
+            rules: vec![Rule {
+                allow: false,
+                pattern: "/".into(),
+            }],
+            crawl_delay: None,
+        }
+    }
+
+    /// Parses a robots.txt and keeps the groups that name the product token `agent`,
+    /// ignoring case, falling back to the `*` groups if none does.
+    pub fn parse(content: &str, agent: &str) -> Robots {
+        let agent = agent.to_lowercase();
+        let mut groups: Vec<Group> = Vec::new();
+        let mut in_agent_lines = false;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let key = key.trim().to_lowercase();
+            let value = value.trim();
+            match key.as_str() {
+                "user-agent" => {
+                    if !in_agent_lines {
+                        groups.push(Group::default());
+                    }
+                    in_agent_lines = true;
+                    if let Some(group) = groups.last_mut() {
+                        group.agents.push(value.to_lowercase());
+                    }
+                }
+                "allow" | "disallow" => {
+                    in_agent_lines = false;
+                    // an empty disallow allows everything
+                    if value.is_empty() {
+                        continue;
+                    }
+                    if let Some(group) = groups.last_mut() {
+                        group.rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.into(),
+                        });
+                    }
+                }
+                "crawl-delay" => {
+                    in_agent_lines = false;
+                    if let (Some(group), Ok(delay)) = (groups.last_mut(), value.parse::<f64>()) {
+                        // NaN and negative delays are ignored, huge ones waited out as
+                        // the longest interval we limit a host to
+                        let delay = Duration::try_from_secs_f64(delay)
+                            .ok()
+                            .or((delay > 0.0).then_some(MAX_INTERVAL));
+                        if let Some(delay) = delay {
+                            group.crawl_delay = Some(delay.min(MAX_INTERVAL));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let named = |g: &Group| g.agents.contains(&agent);
+        let selected: Vec<Group> = if groups.iter().any(named) {
+            groups.into_iter().filter(named).collect()
+        } else {
+            groups
+                .into_iter()
+                .filter(|g| g.agents.iter().any(|a| a == "*"))
+                .collect()
+        };
+        let mut robots = Robots::default();
+        for group in selected {
+            robots.rules.extend(group.rules);
+            robots.crawl_delay = robots.crawl_delay.max(group.crawl_delay);
+        }
+        robots
+    }
+
+    /// The longest matching rule decides, allow wins a tie.
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        self.rules
+            .iter()
+            .filter(|r| matches(&r.pattern, &path))
+            .max_by_key(|r| (r.pattern.len(), r.allow))
+            .map_or(true, |r| r.allow)
+    }
+
+    pub fn crawl_delay(&self) -> Option<Duration> {
+        self.crawl_delay
+    }
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = path.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        if last && anchored {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use reqwest::Url;
+
+    use super::Robots;
+    use crate::{
+        rate_limit::MAX_INTERVAL,
+        requester::{ROBOTS_AGENT, USER_AGENT},
+    };
+
+    const ROBOTS: &str = "
+# comment
+User-agent: *
+Disallow: /wiki/Special:
+Disallow: /*?action=
+Allow: /wiki/Special:Random
+Crawl-delay: 2
+
+User-agent: ATRICO
+User-agent: other
+Disallow: /private
+Allow: /private/public$
+Crawl-delay: 0.5
+
+User-agent: rico
+User-agent: atrico/0.1
+Disallow: /
+";
+
+    fn allowed(robots: &Robots, url: &str) -> bool {
+        robots.is_allowed(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_wildcard_group() {
+        let robots = Robots::parse(ROBOTS, "somebot/1.0");
+        assert!(allowed(&robots, "https://worm.fandom.com/wiki/Worm_Wiki"));
+        assert!(!allowed(
+            &robots,
+            "https://worm.fandom.com/wiki/Special:Search"
+        ));
+        assert!(allowed(
+            &robots,
+            "https://worm.fandom.com/wiki/Special:Random"
+        ));
+        assert!(!allowed(
+            &robots,
+            "https://worm.fandom.com/wiki/Taylor?action=edit"
+        ));
+        assert_eq!(robots.crawl_delay(), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_named_group() {
+        assert!(USER_AGENT.starts_with(&format!("{ROBOTS_AGENT}/")));
+        let robots = Robots::parse(ROBOTS, ROBOTS_AGENT);
+        assert!(allowed(
+            &robots,
+            "https://worm.fandom.com/wiki/Special:Search"
+        ));
+        assert!(!allowed(&robots, "https://worm.fandom.com/private/x"));
+        assert!(allowed(&robots, "https://worm.fandom.com/private/public"));
+        assert!(!allowed(
+            &robots,
+            "https://worm.fandom.com/private/public/x"
+        ));
+        assert_eq!(robots.crawl_delay(), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_crawl_delay_is_clamped() {
+        let delay = |value: &str| {
+            Robots::parse(
+                &format!("User-agent: *\nCrawl-delay: {value}"),
+                ROBOTS_AGENT,
+            )
+            .crawl_delay()
+        };
+        assert_eq!(delay("1e20"), Some(MAX_INTERVAL));
+        assert_eq!(delay("inf"), Some(MAX_INTERVAL));
+        assert_eq!(delay("-1"), None);
+        assert_eq!(delay("NaN"), None);
+        assert_eq!(delay("soon"), None);
+    }
+}
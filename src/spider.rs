@@ -1,22 +1,95 @@
-use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    future::pending,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use reqwest::Url;
-use tokio::{spawn, task::JoinHandle, time::sleep};
-use tracing::warn;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs, select, spawn,
+    sync::mpsc::UnboundedReceiver,
+    task::{JoinHandle, JoinSet},
+    time::{interval_at, sleep_until, Instant, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
 
 use crate::{
+    cache,
+    canonical::Canonicalizer,
+    error::{CrawlError, CrawlReport},
+    fingerprint::Fingerprinter,
+    frontier::{Frontier, FrontierKind, Queued},
     parser::Parser,
     requester::{Requester, SimpleRequest},
+    stats::{self, CrawlStats},
 };
 
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
 pub struct Spider {
     state: SpiderState,
     requester: Arc<Requester>,
-    open_requests: Vec<JoinHandle<Vec<SimpleRequest>>>,
+    /// each yields the fingerprint and url of its request
+    tasks: JoinSet<(String, Url, Result<Vec<Queued>, CrawlError>)>,
+    max_tasks: usize,
+    buffered: BufferedBytes,
+    max_buffered_bytes: Option<usize>,
+    state_dir: PathBuf,
+    max_depth: Option<u32>,
+    canonicalizer: Arc<Canonicalizer>,
+    report: CrawlReport,
+    started: Instant,
+    shutdown_timeout: Duration,
+}
+
+/// Receives a message whenever the crawl should stop, usually on a signal.
+pub type Shutdown = UnboundedReceiver<()>;
+
+pub struct CrawlOptions {
+    pub state_dir: PathBuf,
+    /// continue from the checkpoint in `state_dir` instead of the seeds
+    pub resume: bool,
+    pub frontier: FrontierKind,
+    /// requests further than this many links from a seed are dropped
+    pub max_depth: Option<u32>,
+    /// applied to seeds and discovered urls before they are deduplicated or fetched
+    pub canonicalizer: Canonicalizer,
+    /// requests fetched and parsed at once, the rest wait in the frontier
+    pub max_tasks: usize,
+    /// no new request is started while running ones hold more page bytes than this
+    pub max_buffered_bytes: Option<usize>,
+    /// on the first message no new request is started and the crawl is checkpointed
+    /// after the running ones finished, a second one abandons them right away
+    pub shutdown: Shutdown,
+    /// running requests are abandoned after this long, they are retried on resume
+    pub shutdown_timeout: Duration,
 }
 
 pub trait RequestFilter {
     fn is_valid(&self, request: &SimpleRequest) -> bool;
+
+    /// Added to the priority the parser assigns, see `FrontierKind::Priority`.
+    fn priority(&self, _request: &SimpleRequest) -> i64 {
+        0
+    }
+}
+
+pub struct PrefixRequestFilter {
+    pub prefixes: Vec<String>,
+}
+
+impl RequestFilter for PrefixRequestFilter {
+    fn is_valid(&self, request: &SimpleRequest) -> bool {
+        let u = request.url.as_str();
+        self.prefixes.iter().any(|p| u.starts_with(p.as_str()))
+    }
 }
 
 impl Spider {
@@ -24,78 +97,349 @@ impl Spider {
         initial: Vec<SimpleRequest>,
         parser: P,
         request_filter: R,
-        cache_dir: PathBuf,
-    ) where
+        requester: Requester,
+        options: CrawlOptions,
+    ) -> CrawlReport
+    where
         P: Parser + Clone + Send + 'static,
-        R: RequestFilter,
+        R: RequestFilter + Send + Sync + 'static,
     {
+        let requester = Arc::new(requester);
+        let report = CrawlReport {
+            offline: requester.is_offline(),
+            ..CrawlReport::default()
+        };
+        let fingerprinter = requester.fingerprinter().clone();
+        let checkpoint = if options.resume {
+            SpiderState::load(&options.state_dir, options.frontier, fingerprinter.clone()).await
+        } else {
+            None
+        };
+        let state = match checkpoint {
+            Some(state) => {
+                info!(
+                    open = state.open.len(),
+                    seen = state.seen.len(),
+                    "resuming crawl from checkpoint"
+                );
+                state
+            }
+            None => {
+                let mut allowed = Vec::new();
+                for mut r in initial {
+                    r.url = options.canonicalizer.apply(r.url);
+                    if requester.allowed(&r.url).await {
+                        let priority = parser.priority(&r) + request_filter.priority(&r);
+                        allowed.push(Queued {
+                            request: r,
+                            priority,
+                        });
+                    } else {
+                        warn!(url = %r.url, "seed disallowed by robots.txt");
+                    }
+                }
+                SpiderState::new(allowed, options.frontier, fingerprinter)
+            }
+        };
         let s = Spider {
-            state: SpiderState::new(initial),
-            open_requests: Vec::new(),
-            requester: Arc::new(Requester::new(cache_dir)),
+            state,
+            tasks: JoinSet::new(),
+            max_tasks: options.max_tasks.max(1),
+            buffered: BufferedBytes::default(),
+            max_buffered_bytes: options.max_buffered_bytes,
+            requester,
+            state_dir: options.state_dir,
+            max_depth: options.max_depth,
+            canonicalizer: Arc::new(options.canonicalizer),
+            report,
+            started: Instant::now(),
+            shutdown_timeout: options.shutdown_timeout,
         };
-        s.run_internal(parser, request_filter).await
+        s.run_internal(parser, request_filter, options.shutdown)
+            .await
     }
-    async fn run_internal<P, R>(mut self, parser: P, request_filter: R)
+    async fn run_internal<P, R>(
+        mut self,
+        parser: P,
+        request_filter: R,
+        mut shutdown: Shutdown,
+    ) -> CrawlReport
     where
         P: Parser + Clone + Send + 'static,
-        R: RequestFilter,
+        R: RequestFilter + Send + Sync + 'static,
     {
+        let request_filter = Arc::new(request_filter);
+        let mut checkpoint_timer =
+            interval_at(Instant::now() + CHECKPOINT_INTERVAL, CHECKPOINT_INTERVAL);
+        checkpoint_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut progress_timer = interval_at(Instant::now() + PROGRESS_INTERVAL, PROGRESS_INTERVAL);
+        progress_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // set once shutting down, nothing is scheduled anymore
+        let mut deadline: Option<Instant> = None;
         loop {
-            let mut stepped = false;
-            while let Some(r) = self.state.next() {
-                stepped = true;
-                let req = self.requester.clone();
-                let p = parser.clone();
-                self.open_requests.push(spawn(async move {
-                    let response = req.execute(r.clone()).await;
-                    p.parse(&r, &response).await
-                }));
-            }
-            let mut new_jobs = Vec::new();
-            for job in self.open_requests.into_iter() {
-                if job.is_finished() {
-                    stepped = true;
-                    match job.await {
-                        Ok(new_requests) => {
-                            for r in new_requests.iter() {
-                                if request_filter.is_valid(r) {
-                                    self.state.add(r.clone());
-                                }
-                            }
-                        }
-                        Err(_e) => {
-                            // warn!(error =? e, "failure during request");
-                        }
+            // requests beyond the limits wait in the frontier instead of as parked tasks,
+            // running tasks always finish and free their bytes, so this cannot stall
+            while deadline.is_none() && self.tasks.len() < self.max_tasks && !self.over_byte_limit()
+            {
+                let Some((fingerprint, Queued { request: r, .. })) = self.state.next() else {
+                    break;
+                };
+                let url = r.url.clone();
+                let mut job = AbortOnDrop(spawn(Self::crawl_one(
+                    r,
+                    self.requester.clone(),
+                    parser.clone(),
+                    request_filter.clone(),
+                    self.max_depth,
+                    self.canonicalizer.clone(),
+                    self.buffered.clone(),
+                )));
+                // a panicking parser only fails the inner task, so the request is still
+                // known when it is reported
+                self.tasks.spawn(async move {
+                    let result = (&mut job.0).await.map_err(CrawlError::from).and_then(|r| r);
+                    (fingerprint, url, result)
+                });
+            }
+            self.requester
+                .counters()
+                .set_gauges(self.state.open.len(), self.tasks.len());
+            if self.tasks.is_empty() {
+                return self.stop().await;
+            }
+
+            let timeout = async move {
+                match deadline {
+                    Some(deadline) => sleep_until(deadline).await,
+                    None => pending().await,
+                }
+            };
+            select! {
+                Some(joined) = self.tasks.join_next() => {
+                    let (fingerprint, url, result) =
+                        joined.expect("the outer task only awaits the request");
+                    self.finish(&fingerprint, url, result);
+                }
+                _ = checkpoint_timer.tick() => self.checkpoint().await,
+                _ = progress_timer.tick() => {
+                    let stats = self.stats();
+                    info!(
+                        queued = stats.queued,
+                        in_flight = stats.in_flight,
+                        pages = stats.pages,
+                        fetched = stats.fetched,
+                        cache_hits = stats.cache_hits,
+                        cache_misses = stats.cache_misses,
+                        "progress"
+                    );
+                    eprintln!("{}", stats.progress());
+                }
+                Some(()) = shutdown.recv() => {
+                    if deadline.is_some() {
+                        info!(running = self.tasks.len(), "stopping immediately");
+                        deadline = Some(Instant::now());
+                    } else {
+                        deadline = Some(Instant::now() + self.shutdown_timeout);
+                        self.report.interrupted = true;
+                        info!(running = self.tasks.len(), "shutting down");
+                        eprintln!(
+                            "shutting down, waiting up to {:?} for {} running requests, \
+                             interrupt again to abandon them",
+                            self.shutdown_timeout,
+                            self.tasks.len()
+                        );
                     }
-                } else {
-                    new_jobs.push(job);
+                }
+                _ = timeout => {
+                    warn!(running = self.tasks.len(), "shutdown timed out, abandoning requests");
+                    // the abandoned requests stay in flight and are checkpointed as such,
+                    // aborting a waiting task aborts its request as well
+                    self.tasks.shutdown().await;
+                    return self.stop().await;
                 }
             }
-            self.open_requests = new_jobs;
-
-            // dbg!(self.open_requests.len() + self.state.open.len());
+        }
+    }
 
-            if self.open_requests.is_empty() && self.state.is_empty() {
-                break;
+    async fn crawl_one<P, R>(
+        r: SimpleRequest,
+        req: Arc<Requester>,
+        p: P,
+        filter: Arc<R>,
+        max_depth: Option<u32>,
+        canonicalizer: Arc<Canonicalizer>,
+        buffered: BufferedBytes,
+    ) -> Result<Vec<Queued>, CrawlError>
+    where
+        P: Parser + Clone + Send + 'static,
+        R: RequestFilter + Send + Sync + 'static,
+    {
+        let response = req.clone().execute(r.clone()).await?;
+        let _held = buffered.hold(response.body.len());
+        debug!(
+            url = %r.url,
+            status = %response.status,
+            depth = r.depth,
+            referrer = r.referrer.as_ref().map(Url::as_str),
+            "fetched page"
+        );
+        let counters = req.counters();
+        let parsed = p.clone().parse(&r, &response).await;
+        if parsed.is_err() {
+            stats::inc(&counters.parse_failures, 1);
+        }
+        let mut allowed = Vec::new();
+        for mut n in parsed? {
+            n.url = canonicalizer.apply(n.url);
+            n.depth = r.depth + 1;
+            n.referrer = Some(r.url.clone());
+            // robots.txt is only consulted for requests we want anyway
+            if max_depth.is_some_and(|m| n.depth > m) || !filter.is_valid(&n) {
+                stats::inc(&counters.filtered, 1);
+                continue;
+            }
+            if req.allowed(&n.url).await {
+                let priority = p.priority(&n) + filter.priority(&n);
+                allowed.push(Queued {
+                    request: n,
+                    priority,
+                });
             } else {
-                if !stepped {
-                    sleep(Duration::from_millis(10)).await;
+                debug!(url = %n.url, "disallowed by robots.txt");
+                stats::inc(&counters.filtered, 1);
+            }
+        }
+        Ok(allowed)
+    }
+
+    /// Checkpoints the frontier and finishes the report.
+    async fn stop(mut self) -> CrawlReport {
+        self.checkpoint().await;
+        self.report.abandoned = self.state.in_flight.len();
+        self.report.stats = self.stats();
+        self.report
+    }
+
+    fn stats(&self) -> CrawlStats {
+        self.requester.counters().snapshot(self.started.elapsed())
+    }
+
+    fn over_byte_limit(&self) -> bool {
+        self.max_buffered_bytes
+            .is_some_and(|max| self.buffered.get() >= max)
+    }
+
+    fn finish(&mut self, fingerprint: &str, url: Url, result: Result<Vec<Queued>, CrawlError>) {
+        self.state.finish(fingerprint);
+        match result {
+            Ok(new_requests) => {
+                self.report.succeeded += 1;
+                self.requester.counters().crawled(&url);
+                for r in new_requests {
+                    self.state.add(r);
                 }
             }
+            Err(CrawlError::NotCached) => {
+                debug!(url = %url, "not cached");
+                self.report.record(url, &CrawlError::NotCached);
+            }
+            Err(e) => {
+                warn!(url = %url, stage = ?e.stage(), error = %e, "failure during request");
+                self.report.record(url, &e);
+            }
         }
     }
+
+    /// Fingerprints of the requests seen by the crawl checkpointed in `state_dir`.
+    pub async fn last_seen(
+        state_dir: &PathBuf,
+        fingerprinter: Fingerprinter,
+    ) -> Option<HashSet<String>> {
+        SpiderState::load(state_dir, FrontierKind::default(), fingerprinter)
+            .await
+            .map(|s| s.seen)
+    }
+
+    /// Saves the frontier along with the access times of the cache.
+    async fn checkpoint(&self) {
+        if let Err(e) = self.state.save(&self.state_dir).await {
+            warn!(error = ?e, state_dir = ?self.state_dir, "failed to checkpoint spider state");
+        }
+        if let Err(e) = self.requester.flush_cache().await {
+            warn!(error = ?e, "failed to persist cache access times");
+        }
+    }
+}
+/// Aborts the task when dropped, a detached request would keep fetching and writing to
+/// the cache after it was abandoned.
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Page bytes held by running tasks.
+#[derive(Clone, Default)]
+struct BufferedBytes(Arc<AtomicUsize>);
+
+/// Gives its bytes back when dropped.
+struct Held {
+    buffered: Arc<AtomicUsize>,
+    bytes: usize,
+}
+
+impl BufferedBytes {
+    fn hold(&self, bytes: usize) -> Held {
+        self.0.fetch_add(bytes, Ordering::Relaxed);
+        Held {
+            buffered: self.0.clone(),
+            bytes,
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        self.buffered.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
 }
+
 struct SpiderState {
-    open: Vec<SimpleRequest>,
-    seen: HashSet<Url>,
+    open: Box<dyn Frontier>,
+    /// request fingerprints
+    seen: HashSet<String>,
+    in_flight: HashMap<String, Queued>,
+    fingerprinter: Fingerprinter,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    /// in the order they were pushed
+    open: Vec<Queued>,
+    /// fingerprints, or urls in checkpoints written before requests were fingerprinted
+    seen: Vec<String>,
+    in_flight: Vec<Queued>,
 }
 
 impl SpiderState {
-    fn new(initial_requests: Vec<SimpleRequest>) -> SpiderState {
+    const CHECKPOINT_FILE: &'static str = "frontier.json";
+
+    fn new(
+        initial_requests: Vec<Queued>,
+        frontier: FrontierKind,
+        fingerprinter: Fingerprinter,
+    ) -> SpiderState {
         let mut s = SpiderState {
-            open: Vec::new(),
+            open: frontier.build(),
             seen: HashSet::new(),
+            in_flight: HashMap::new(),
+            fingerprinter,
         };
         for r in initial_requests {
             s.add(r);
@@ -103,17 +447,394 @@ impl SpiderState {
         s
     }
 
-    fn add(&mut self, r: SimpleRequest) {
-        if self.seen.insert(r.url.clone()) {
-            self.open.push(r);
+    async fn load(
+        state_dir: &PathBuf,
+        frontier: FrontierKind,
+        fingerprinter: Fingerprinter,
+    ) -> Option<SpiderState> {
+        let content = fs::read(state_dir.join(Self::CHECKPOINT_FILE)).await.ok()?;
+        let checkpoint: Checkpoint = match serde_json::from_slice(&content) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(error = ?e, state_dir = ?state_dir, "ignoring unreadable checkpoint");
+                return None;
+            }
+        };
+        // a seen url was always a GET, whose fingerprint is the url key
+        let seen = checkpoint
+            .seen
+            .into_iter()
+            .map(|s| Url::parse(&s).map_or(s, |url| cache::key(&url)))
+            .collect();
+        let mut state = SpiderState {
+            open: frontier.build(),
+            seen,
+            in_flight: HashMap::new(),
+            fingerprinter,
+        };
+        // requests that were running when the checkpoint was taken never finished.
+        // Fingerprinted with other headers they may collide now, only one is kept.
+        let mut loaded = HashSet::new();
+        for queued in checkpoint.open.into_iter().chain(checkpoint.in_flight) {
+            let fingerprint = state.fingerprinter.fingerprint(&queued.request);
+            if loaded.insert(fingerprint.clone()) {
+                state.seen.insert(fingerprint);
+                state.open.push(queued);
+            }
+        }
+        Some(state)
+    }
+
+    async fn save(&self, state_dir: &PathBuf) -> std::io::Result<()> {
+        let checkpoint = Checkpoint {
+            open: self.open.queued(),
+            seen: self.seen.iter().cloned().collect(),
+            in_flight: self.in_flight.values().cloned().collect(),
+        };
+        fs::create_dir_all(state_dir).await?;
+        let path = state_dir.join(Self::CHECKPOINT_FILE);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&checkpoint)?).await?;
+        fs::rename(tmp, path).await
+    }
+
+    fn add(&mut self, queued: Queued) {
+        if self
+            .seen
+            .insert(self.fingerprinter.fingerprint(&queued.request))
+        {
+            self.open.push(queued);
+        }
+    }
+
+    fn next(&mut self) -> Option<(String, Queued)> {
+        let queued = self.open.pop()?;
+        let fingerprint = self.fingerprinter.fingerprint(&queued.request);
+        self.in_flight.insert(fingerprint.clone(), queued.clone());
+        Some((fingerprint, queued))
+    }
+
+    fn finish(&mut self, fingerprint: &str) {
+        if self.in_flight.remove(fingerprint).is_none() {
+            warn!(fingerprint, "finished request was not in flight");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use reqwest::{
+        header::{HeaderMap, HeaderValue, ACCEPT_LANGUAGE},
+        Method, Url,
+    };
+    use test::Bencher;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+        runtime::Runtime,
+        sync::mpsc::unbounded_channel,
+    };
+
+    use super::{CrawlOptions, PrefixRequestFilter, Shutdown, Spider, SpiderState};
+    use crate::{
+        cache::{test::temp_dir, CachePolicy},
+        canonical::Canonicalizer,
+        error::{CrawlReport, ParseError},
+        fingerprint::Fingerprinter,
+        frontier::{FrontierKind, Queued},
+        memory_cache::MemoryCache,
+        parser::Parser,
+        rate_limit::{HostLimits, RateLimits},
+        requester::{Page, Requester, SimpleRequest},
+        retry::RetryPolicy,
+    };
+
+    const LINKS_PER_PAGE: usize = 8;
+    /// connections waiting for a response to `/hang`
+    static HANGING: AtomicUsize = AtomicUsize::new(0);
+
+    /// Serves `/page/0` to `/page/<pages - 1>` on a local port, page n links to pages
+    /// 8n + 1 to 8n + 8 so every page is reachable from page 0.
+    async fn serve_site(pages: usize) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_connection(stream, pages));
+            }
+        });
+        Url::parse(&format!("http://{addr}/page/0")).unwrap()
+    }
+
+    async fn serve_connection(stream: TcpStream, pages: usize) {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(request_line)) = lines.next_line().await {
+            while let Ok(Some(header)) = lines.next_line().await {
+                if header.is_empty() {
+                    break;
+                }
+            }
+            let path = request_line.split(' ').nth(1).unwrap_or_default();
+            if path == "/hang" {
+                // until the client gives up on the request
+                HANGING.fetch_add(1, Ordering::SeqCst);
+                while let Ok(Some(_)) = lines.next_line().await {}
+                HANGING.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+            let page = path
+                .strip_prefix("/page/")
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|n| *n < pages);
+            let (status, body) = match page {
+                Some(n) => ("200 OK", site_page(n, pages)),
+                None => ("404 Not Found", String::new()),
+            };
+            let response = format!(
+                "HTTP/1.1 {status}\r\ncontent-type: text/html\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            if write.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn site_page(n: usize, pages: usize) -> String {
+        let links: String = (1..=LINKS_PER_PAGE)
+            .map(|i| {
+                format!(
+                    "<a href=\"/page/{}\">{i}</a>",
+                    (n * LINKS_PER_PAGE + i) % pages
+                )
+            })
+            .collect();
+        format!("<html><body>{links}</body></html>")
+    }
+
+    #[derive(Clone)]
+    struct LinkParser;
+
+    impl Parser for LinkParser {
+        async fn parse(
+            self,
+            request: &SimpleRequest,
+            page: &Page,
+        ) -> Result<Vec<SimpleRequest>, ParseError> {
+            Ok(page
+                .body
+                .split("href=\"")
+                .skip(1)
+                .filter_map(|s| request.url.join(s.split('"').next()?).ok())
+                .map(|url| SimpleRequest {
+                    method: Method::GET,
+                    url,
+                    headers: HeaderMap::new(),
+                    body: None,
+                    depth: 0,
+                    referrer: None,
+                })
+                .collect())
+        }
+    }
+
+    fn options(state_dir: PathBuf) -> CrawlOptions {
+        CrawlOptions {
+            state_dir,
+            resume: false,
+            frontier: FrontierKind::Bfs,
+            max_depth: None,
+            canonicalizer: Canonicalizer::default(),
+            max_tasks: 32,
+            max_buffered_bytes: None,
+            shutdown: interrupts(0),
+            shutdown_timeout: Duration::from_secs(30),
         }
     }
 
-    fn next(&mut self) -> Option<SimpleRequest> {
-        self.open.pop()
+    /// As if ctrl-c was pressed `n` times right away.
+    fn interrupts(n: usize) -> Shutdown {
+        let (sender, receiver) = unbounded_channel();
+        for _ in 0..n {
+            sender.send(()).unwrap();
+        }
+        receiver
+    }
+
+    async fn crawl_site(seed: &Url, options: CrawlOptions) -> CrawlReport {
+        let unlimited = HostLimits {
+            requests_per_second: None,
+            burst: 1,
+            max_in_flight: 32,
+        };
+        let requester = Requester::new(
+            Box::new(MemoryCache::new()),
+            CachePolicy::default(),
+            RetryPolicy::default(),
+            RateLimits {
+                max_in_flight: 32,
+                default_host: unlimited,
+                ..RateLimits::default()
+            },
+            Fingerprinter::default(),
+            false,
+        );
+        let seed_request = SimpleRequest {
+            method: Method::GET,
+            url: seed.clone(),
+            headers: HeaderMap::new(),
+            body: None,
+            depth: 0,
+            referrer: None,
+        };
+        let filter = PrefixRequestFilter {
+            prefixes: vec![seed.join("/page/").unwrap().to_string()],
+        };
+        Spider::run(vec![seed_request], LinkParser, filter, requester, options).await
+    }
+
+    #[tokio::test]
+    async fn test_crawl_synthetic_site() {
+        let seed = serve_site(300).await;
+        let dir = temp_dir("spider");
+        // a single page is over the byte limit, the crawl still makes progress
+        for (max_tasks, max_buffered_bytes) in [(32, None), (4, Some(1))] {
+            let options = CrawlOptions {
+                max_tasks,
+                max_buffered_bytes,
+                ..options(dir.clone())
+            };
+            let crawl = crawl_site(&seed, options);
+            let report = tokio::time::timeout(Duration::from_secs(60), crawl)
+                .await
+                .unwrap();
+            assert_eq!(report.succeeded, 300);
+            assert!(report.failures.is_empty(), "{:?}", report.failures);
+            let stats = report.stats;
+            assert_eq!((stats.queued, stats.in_flight), (0, 0));
+            assert_eq!(
+                (stats.fetched, stats.cache_misses, stats.cache_hits),
+                (300, 300, 0)
+            );
+            assert_eq!(stats.hosts["127.0.0.1"], 300);
+        }
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_and_resume() {
+        let seed = serve_site(300).await;
+        let dir = temp_dir("spider-shutdown");
+        // stops while the seed is fetched, its links are checkpointed
+        let interrupted = CrawlOptions {
+            shutdown: interrupts(1),
+            ..options(dir.clone())
+        };
+        let report = crawl_site(&seed, interrupted).await;
+        assert!(report.interrupted);
+        assert_eq!((report.succeeded, report.abandoned), (1, 0));
+        assert_eq!(report.stats.queued, LINKS_PER_PAGE);
+
+        let resumed = CrawlOptions {
+            resume: true,
+            ..options(dir.clone())
+        };
+        let report = crawl_site(&seed, resumed).await;
+        assert!(!report.interrupted);
+        assert_eq!(report.succeeded, 299);
+
+        // a request that never finishes is abandoned and still in flight on resume
+        let hanging = seed.join("/hang").unwrap();
+        let timed_out = CrawlOptions {
+            shutdown: interrupts(1),
+            shutdown_timeout: Duration::from_millis(100),
+            ..options(dir.clone())
+        };
+        let stopped_now = CrawlOptions {
+            shutdown: interrupts(2),
+            ..options(dir.clone())
+        };
+        for options in [timed_out, stopped_now] {
+            let report =
+                tokio::time::timeout(Duration::from_secs(10), crawl_site(&hanging, options))
+                    .await
+                    .unwrap();
+            assert_eq!((report.succeeded, report.abandoned), (0, 1));
+            let checkpoint = SpiderState::load(&dir, FrontierKind::Bfs, Fingerprinter::default())
+                .await
+                .unwrap();
+            assert_eq!(checkpoint.open.queued()[0].request.url, hanging);
+            // the abandoned request does not linger
+            for _ in 0..100 {
+                if HANGING.load(Ordering::SeqCst) == 0 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(HANGING.load(Ordering::SeqCst), 0);
+        }
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_with_changed_fingerprint_headers() {
+        let dir = temp_dir("spider-fingerprints");
+        let request = |language| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_LANGUAGE, HeaderValue::from_static(language));
+            Queued {
+                request: SimpleRequest {
+                    method: Method::GET,
+                    url: Url::parse("https://worm.fandom.com/wiki/Skitter").unwrap(),
+                    headers,
+                    body: None,
+                    depth: 0,
+                    referrer: None,
+                },
+                priority: 0,
+            }
+        };
+        let by_language = Fingerprinter {
+            headers: vec![ACCEPT_LANGUAGE],
+        };
+        let mut state = SpiderState::new(
+            vec![request("en"), request("de")],
+            FrontierKind::Bfs,
+            by_language,
+        );
+        let (running, _) = state.next().unwrap();
+        state.save(&dir).await.unwrap();
+
+        // without the header both are the same request
+        let mut state = SpiderState::load(&dir, FrontierKind::Bfs, Fingerprinter::default())
+            .await
+            .unwrap();
+        assert_eq!(state.open.len(), 1);
+        let (fingerprint, _) = state.next().unwrap();
+        assert!(state.next().is_none());
+        state.finish(&fingerprint);
+        state.finish(&running);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
     }
 
-    fn is_empty(&self) -> bool {
-        self.open.is_empty()
+    /// Crawls a local site of 5000 pages from scratch, what is measured is the
+    /// scheduling overhead on top of fetching and parsing.
+    #[bench]
+    fn bench_crawl_synthetic_site(b: &mut Bencher) {
+        let runtime = Runtime::new().unwrap();
+        let seed = runtime.block_on(serve_site(5000));
+        let dir = temp_dir("bench-spider");
+        b.iter(|| {
+            let report = runtime.block_on(crawl_site(&seed, options(dir.clone())));
+            assert_eq!(report.succeeded, 5000);
+        });
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
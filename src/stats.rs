@@ -0,0 +1,151 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use reqwest::Url;
+
+/// Live counters of a running crawl, owned by the `Requester` and shared with the
+/// spider tasks.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// set by the spider whenever it schedules
+    pub queued: AtomicU64,
+    pub in_flight: AtomicU64,
+    /// responses received from the network
+    pub fetched: AtomicU64,
+    /// body bytes received from the network
+    pub bytes: AtomicU64,
+    pub cache_hits: AtomicU64,
+    /// pages that were missing or stale, a revalidated page is a miss
+    pub cache_misses: AtomicU64,
+    /// discovered requests dropped by depth, filter or robots.txt
+    pub filtered: AtomicU64,
+    pub parse_failures: AtomicU64,
+    /// crawled pages per host
+    hosts: Mutex<HashMap<String, u64>>,
+}
+
+pub fn inc(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+impl Counters {
+    pub fn crawled(&self, url: &Url) {
+        let host = url.host_str().unwrap_or_default().to_string();
+        *self.hosts.lock().unwrap().entry(host).or_default() += 1;
+    }
+
+    pub fn set_gauges(&self, queued: usize, in_flight: usize) {
+        self.queued.store(queued as u64, Ordering::Relaxed);
+        self.in_flight.store(in_flight as u64, Ordering::Relaxed);
+    }
+
+    pub fn hosts(&self) -> BTreeMap<String, u64> {
+        let hosts = self.hosts.lock().unwrap();
+        hosts.iter().map(|(h, p)| (h.clone(), *p)).collect()
+    }
+
+    pub fn snapshot(&self, elapsed: Duration) -> CrawlStats {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let hosts = self.hosts();
+        CrawlStats {
+            queued: get(&self.queued) as usize,
+            in_flight: get(&self.in_flight) as usize,
+            pages: hosts.values().sum(),
+            fetched: get(&self.fetched),
+            bytes: get(&self.bytes),
+            cache_hits: get(&self.cache_hits),
+            cache_misses: get(&self.cache_misses),
+            filtered: get(&self.filtered),
+            parse_failures: get(&self.parse_failures),
+            elapsed,
+            hosts,
+        }
+    }
+}
+
+/// A point in time of a crawl, the last one is returned with the `CrawlReport`.
+#[derive(Clone, Debug, Default)]
+pub struct CrawlStats {
+    /// waiting in the frontier
+    pub queued: usize,
+    pub in_flight: usize,
+    /// fetched or served from the cache and parsed
+    pub pages: u64,
+    pub fetched: u64,
+    pub bytes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub filtered: u64,
+    pub parse_failures: u64,
+    pub elapsed: Duration,
+    /// crawled pages per host
+    pub hosts: BTreeMap<String, u64>,
+}
+
+impl CrawlStats {
+    pub fn pages_per_second(&self, pages: u64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        pages as f64 / secs
+    }
+
+    /// One line for the periodic progress output.
+    pub fn progress(&self) -> String {
+        let mut line = format!(
+            "{} queued, {} in flight, {} pages ({:.1}/s), {} fetched, {} bytes, cache {} hits {} misses, {} filtered, {} parse failures",
+            self.queued,
+            self.in_flight,
+            self.pages,
+            self.pages_per_second(self.pages),
+            self.fetched,
+            self.bytes,
+            self.cache_hits,
+            self.cache_misses,
+            self.filtered,
+            self.parse_failures,
+        );
+        for (host, pages) in self.hosts.iter() {
+            line.push_str(&format!(", {host} {:.1}/s", self.pages_per_second(*pages)));
+        }
+        line
+    }
+}
+
+impl Display for CrawlStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} pages in {:.1}s, {:.1} pages/s",
+            self.pages,
+            self.elapsed.as_secs_f64(),
+            self.pages_per_second(self.pages)
+        )?;
+        writeln!(
+            f,
+            "fetched {} pages with {} bytes, cache {} hits {} misses",
+            self.fetched, self.bytes, self.cache_hits, self.cache_misses
+        )?;
+        writeln!(
+            f,
+            "{} requests filtered, {} parse failures",
+            self.filtered, self.parse_failures
+        )?;
+        for (host, pages) in self.hosts.iter() {
+            writeln!(
+                f,
+                "  {host}: {pages} pages, {:.1} pages/s",
+                self.pages_per_second(*pages)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,597 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, SeekFrom},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader},
+    sync::RwLock,
+};
+use tracing::warn;
+
+use crate::cache::{
+    now, CacheBackend, CacheMetadata, CachedPage, IndexEntry, Reclaimed, VerifyReport, TMP_SUFFIX,
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    /// followed by `size` bytes of body
+    Put {
+        key: String,
+        object: String,
+        size: u64,
+        metadata: CacheMetadata,
+        #[serde(default)]
+        accessed_at: u64,
+    },
+    Metadata {
+        key: String,
+        metadata: CacheMetadata,
+    },
+    /// appended for reads by `flush`
+    Access {
+        key: String,
+        accessed_at: u64,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+#[derive(Clone)]
+struct Slot {
+    entry: IndexEntry,
+    metadata: CacheMetadata,
+    /// where the body starts
+    offset: u64,
+    /// length of the put record including the body
+    length: u64,
+    /// read since the last flush
+    read: bool,
+}
+
+struct StoreFile {
+    file: File,
+    len: u64,
+    /// records skipped on open, they stay in the file until it is compacted
+    corrupt: usize,
+}
+
+/// All pages in one append only file. Every record is a little endian u32 length, a
+/// json header and for puts the body. The last record of a key wins, replaced and
+/// removed records stay in the file until it is compacted.
+pub struct StoreCache {
+    path: PathBuf,
+    index: Mutex<HashMap<String, Slot>>,
+    /// appends and compaction hold this exclusively, readers share it so compaction
+    /// never moves a record under a reader
+    store: RwLock<StoreFile>,
+}
+
+impl StoreCache {
+    const STORE_FILE: &'static str = "pages.store";
+    /// Larger header lengths are corrupt, not headers.
+    const MAX_HEADER_LEN: u64 = 1 << 20;
+
+    pub async fn open(dir: PathBuf) -> std::io::Result<StoreCache> {
+        fs::create_dir_all(&dir).await?;
+        let path = dir.join(Self::STORE_FILE);
+        let mut file = Self::open_file(&path).await?;
+        let (index, len, corrupt) = Self::read_records(&mut file).await?;
+        let actual = file.metadata().await?.len();
+        if len < actual {
+            // a torn record from a crash, everything before it is intact
+            warn!(path = ?path, bytes = actual - len, "truncating torn cache store");
+            file.set_len(len).await?;
+        }
+        Ok(StoreCache {
+            path,
+            index: Mutex::new(index),
+            store: RwLock::new(StoreFile { file, len, corrupt }),
+        })
+    }
+
+    async fn open_file(path: &PathBuf) -> std::io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .await
+    }
+
+    /// Replays the records. Returns the live slots, the end of the last intact record
+    /// and the number of corrupt records skipped on the way.
+    async fn read_records(file: &mut File) -> std::io::Result<(HashMap<String, Slot>, u64, usize)> {
+        let file_len = file.metadata().await?.len();
+        let mut reader = BufReader::new(file);
+        let mut index = HashMap::new();
+        let mut pos = 0;
+        let mut corrupt = 0;
+        loop {
+            let Some((record, offset)) = Self::read_record(&mut reader, pos, file_len).await?
+            else {
+                // nothing intact follows a torn tail, anything else is skipped so the
+                // records after it are not lost
+                let Some(next) = Self::find_record(&mut reader, pos + 1, file_len).await? else {
+                    break;
+                };
+                warn!(
+                    offset = pos,
+                    bytes = next - pos,
+                    "skipping corrupt record in cache store"
+                );
+                corrupt += 1;
+                pos = next;
+                reader.seek(SeekFrom::Start(pos)).await?;
+                continue;
+            };
+            match record {
+                Record::Put {
+                    key,
+                    object,
+                    size,
+                    metadata,
+                    accessed_at,
+                } => {
+                    reader.seek(SeekFrom::Current(size as i64)).await?;
+                    let mut slot = Self::slot(key.clone(), object, size, metadata, offset);
+                    slot.entry.accessed_at = accessed_at;
+                    slot.length = offset + size - pos;
+                    index.insert(key, slot);
+                    pos = offset + size;
+                }
+                Record::Metadata { key, metadata } => {
+                    if let Some(slot) = index.get_mut(&key) {
+                        slot.entry.fetched_at = metadata.fetched_at;
+                        slot.metadata = metadata;
+                    }
+                    pos = offset;
+                }
+                Record::Access { key, accessed_at } => {
+                    if let Some(slot) = index.get_mut(&key) {
+                        slot.entry.accessed_at = accessed_at;
+                    }
+                    pos = offset;
+                }
+                Record::Remove { key } => {
+                    index.remove(&key);
+                    pos = offset;
+                }
+            }
+        }
+        Ok((index, pos, corrupt))
+    }
+
+    /// Reads the header of the record at `pos`, where the reader is. Returns it with the
+    /// offset of its body, or None if the record is torn or corrupt.
+    async fn read_record(
+        reader: &mut BufReader<&mut File>,
+        pos: u64,
+        file_len: u64,
+    ) -> std::io::Result<Option<(Record, u64)>> {
+        let header_len = match reader.read_u32_le().await {
+            Ok(l) => l as u64,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let offset = pos + 4 + header_len;
+        if header_len > Self::MAX_HEADER_LEN || offset > file_len {
+            return Ok(None);
+        }
+        let mut header = vec![0; header_len as usize];
+        reader.read_exact(&mut header).await?;
+        let Ok(record) = serde_json::from_slice::<Record>(&header) else {
+            return Ok(None);
+        };
+        if let Record::Put { size, .. } = record {
+            if offset + size > file_len {
+                return Ok(None);
+            }
+        }
+        Ok(Some((record, offset)))
+    }
+
+    /// The position of the first intact record after `from`, found by the start every
+    /// json header has.
+    async fn find_record(
+        reader: &mut BufReader<&mut File>,
+        from: u64,
+        file_len: u64,
+    ) -> std::io::Result<Option<u64>> {
+        const HEADER_START: &[u8] = b"{\"kind\":\"";
+        let mut chunk = vec![0; 64 * 1024];
+        // where the header of a candidate would start
+        let mut start = from + 4;
+        while start < file_len {
+            reader.seek(SeekFrom::Start(start)).await?;
+            let mut filled = 0;
+            while filled < chunk.len() {
+                match reader.read(&mut chunk[filled..]).await? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            let found = chunk[..filled]
+                .windows(HEADER_START.len())
+                .position(|w| w == HEADER_START);
+            let Some(i) = found else {
+                if filled < chunk.len() {
+                    break;
+                }
+                // a header start may straddle the chunks
+                start += (filled - HEADER_START.len() + 1) as u64;
+                continue;
+            };
+            let candidate = start + i as u64 - 4;
+            reader.seek(SeekFrom::Start(candidate)).await?;
+            if Self::read_record(reader, candidate, file_len)
+                .await?
+                .is_some()
+            {
+                return Ok(Some(candidate));
+            }
+            start += i as u64 + 1;
+        }
+        Ok(None)
+    }
+
+    fn slot(key: String, object: String, size: u64, metadata: CacheMetadata, offset: u64) -> Slot {
+        Slot {
+            entry: IndexEntry {
+                url: metadata.url.clone(),
+                key,
+                object,
+                fetched_at: metadata.fetched_at,
+                size,
+                accessed_at: 0,
+            },
+            metadata,
+            offset,
+            length: 0,
+            read: false,
+        }
+    }
+
+    /// Appends a record, returns the offset of `content` in the file.
+    async fn append(
+        store: &mut StoreFile,
+        record: &Record,
+        content: &[u8],
+    ) -> std::io::Result<u64> {
+        let header = serde_json::to_vec(record)?;
+        let mut buf = Vec::with_capacity(4 + header.len() + content.len());
+        buf.extend((header.len() as u32).to_le_bytes());
+        buf.extend(header);
+        let offset = store.len + buf.len() as u64;
+        buf.extend(content);
+        store.file.write_all(&buf).await?;
+        store.file.flush().await?;
+        store.len += buf.len() as u64;
+        Ok(offset)
+    }
+
+    async fn read_content(&self, offset: u64, size: u64) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(&self.path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut content = vec![0; size as usize];
+        file.read_exact(&mut content).await?;
+        Ok(content)
+    }
+
+    async fn forget(&self, store: &mut StoreFile, keys: &[String]) -> std::io::Result<Reclaimed> {
+        let mut reclaimed = Reclaimed::default();
+        for key in keys {
+            if self.index.lock().unwrap().remove(key).is_none() {
+                continue;
+            }
+            Self::append(store, &Record::Remove { key: key.clone() }, &[]).await?;
+            reclaimed.entries += 1;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Rewrites the file with only the live records.
+    async fn compact(&self, store: &mut StoreFile) -> std::io::Result<u64> {
+        let mut slots: Vec<Slot> = self.index.lock().unwrap().values().cloned().collect();
+        slots.sort_by_key(|s| s.offset);
+        let live: u64 = slots.iter().map(|s| s.length).sum();
+        if live == store.len {
+            return Ok(0);
+        }
+
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(format!(".{:08x}{TMP_SUFFIX}", rand::random::<u32>()));
+        let tmp = PathBuf::from(tmp);
+        let mut compacted = StoreFile {
+            file: Self::open_file(&tmp).await?,
+            len: 0,
+            corrupt: 0,
+        };
+        for slot in slots.iter_mut() {
+            let content = self.read_content(slot.offset, slot.entry.size).await?;
+            let record = Record::Put {
+                key: slot.entry.key.clone(),
+                object: slot.entry.object.clone(),
+                size: slot.entry.size,
+                metadata: slot.metadata.clone(),
+                accessed_at: slot.entry.accessed_at,
+            };
+            let start = compacted.len;
+            slot.offset = Self::append(&mut compacted, &record, &content).await?;
+            slot.length = compacted.len - start;
+        }
+        compacted.file.sync_all().await?;
+        fs::rename(&tmp, &self.path).await?;
+
+        let reclaimed = store.len - compacted.len;
+        *store = compacted;
+        let mut index = self.index.lock().unwrap();
+        for slot in slots {
+            if let Some(s) = index.get_mut(&slot.entry.key) {
+                s.offset = slot.offset;
+                s.length = slot.length;
+            }
+        }
+        Ok(reclaimed)
+    }
+}
+
+#[async_trait]
+impl CacheBackend for StoreCache {
+    async fn get(&self, key: &str) -> std::io::Result<Option<CachedPage>> {
+        let store = self.store.read().await;
+        let Some(slot) = self.index.lock().unwrap().get(key).cloned() else {
+            return Ok(None);
+        };
+        let content = self.read_content(slot.offset, slot.entry.size).await?;
+        drop(store);
+        if hex::encode(Sha256::digest(&content)) != slot.entry.object {
+            warn!(url = %slot.metadata.url, "corrupt record in cache store, removing it");
+            let mut store = self.store.write().await;
+            self.forget(&mut store, &[key.to_string()]).await?;
+            return Ok(None);
+        }
+        if let Some(slot) = self.index.lock().unwrap().get_mut(key) {
+            slot.entry.accessed_at = now();
+            slot.read = true;
+        }
+        Ok(Some(CachedPage {
+            content,
+            metadata: slot.metadata,
+        }))
+    }
+
+    async fn add(
+        &self,
+        key: &str,
+        content: &[u8],
+        metadata: &CacheMetadata,
+    ) -> std::io::Result<()> {
+        let key = key.to_string();
+        let object = hex::encode(Sha256::digest(content));
+        let size = content.len() as u64;
+        let accessed_at = now();
+        let record = Record::Put {
+            key: key.clone(),
+            object: object.clone(),
+            size,
+            metadata: metadata.clone(),
+            accessed_at,
+        };
+        let mut store = self.store.write().await;
+        let start = store.len;
+        let offset = Self::append(&mut store, &record, content).await?;
+        let mut slot = Self::slot(key.clone(), object, size, metadata.clone(), offset);
+        slot.entry.accessed_at = accessed_at;
+        slot.length = store.len - start;
+        self.index.lock().unwrap().insert(key, slot);
+        Ok(())
+    }
+
+    async fn update_metadata(&self, key: &str, metadata: &CacheMetadata) -> std::io::Result<()> {
+        let mut store = self.store.write().await;
+        if !self.index.lock().unwrap().contains_key(key) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        let record = Record::Metadata {
+            key: key.to_string(),
+            metadata: metadata.clone(),
+        };
+        Self::append(&mut store, &record, &[]).await?;
+        if let Some(slot) = self.index.lock().unwrap().get_mut(key) {
+            slot.entry.fetched_at = metadata.fetched_at;
+            slot.metadata = metadata.clone();
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<IndexEntry> {
+        let index = self.index.lock().unwrap();
+        index.values().map(|s| s.entry.clone()).collect()
+    }
+
+    /// Appends an access record per page read since the last flush.
+    async fn flush(&self) -> std::io::Result<()> {
+        let mut store = self.store.write().await;
+        let read: Vec<Record> = self
+            .index
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter(|s| s.read)
+            .map(|s| {
+                s.read = false;
+                Record::Access {
+                    key: s.entry.key.clone(),
+                    accessed_at: s.entry.accessed_at,
+                }
+            })
+            .collect();
+        for record in read {
+            Self::append(&mut store, &record, &[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, keys: &[String]) -> std::io::Result<Reclaimed> {
+        let mut store = self.store.write().await;
+        let mut reclaimed = self.forget(&mut store, keys).await?;
+        if reclaimed.entries > 0 {
+            reclaimed.objects = reclaimed.entries;
+            reclaimed.bytes = self.compact(&mut store).await?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Compacts away replaced records.
+    async fn sweep(&self) -> std::io::Result<Reclaimed> {
+        let mut store = self.store.write().await;
+        Ok(Reclaimed {
+            bytes: self.compact(&mut store).await?,
+            ..Reclaimed::default()
+        })
+    }
+
+    /// Leftovers of an interrupted compaction count as temporary files.
+    async fn verify(&self, repair: bool) -> std::io::Result<VerifyReport> {
+        let mut store = self.store.write().await;
+        let slots: Vec<Slot> = self.index.lock().unwrap().values().cloned().collect();
+        let mut report = VerifyReport {
+            entries: slots.len() + store.corrupt,
+            objects: slots.len(),
+            ..VerifyReport::default()
+        };
+        let mut broken = Vec::new();
+        for slot in slots {
+            let content = self.read_content(slot.offset, slot.entry.size).await?;
+            if hex::encode(Sha256::digest(content)) != slot.entry.object {
+                warn!(url = %slot.entry.url, "corrupt record in cache store");
+                broken.push(slot.entry.key);
+            }
+        }
+        report.broken_entries = broken.len() + store.corrupt;
+        report.corrupt_objects = broken.len();
+
+        let name = self.path.file_name().unwrap().to_string_lossy().to_string();
+        let mut files = fs::read_dir(self.path.parent().unwrap()).await?;
+        while let Some(file) = files.next_entry().await? {
+            let file_name = file.file_name().to_string_lossy().to_string();
+            if file_name.starts_with(&name) && file_name.ends_with(TMP_SUFFIX) {
+                report.temp_files += 1;
+                if repair {
+                    fs::remove_file(file.path()).await?;
+                }
+            }
+        }
+
+        if repair && (!broken.is_empty() || store.corrupt > 0) {
+            self.forget(&mut store, &broken).await?;
+            self.compact(&mut store).await?;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::StoreCache;
+    use crate::cache::{
+        key,
+        test::{add_page, add_pages, temp_dir},
+        CacheBackend,
+    };
+
+    #[tokio::test]
+    async fn test_reopen_compact_and_torn_tail() {
+        let dir = temp_dir("store");
+        let cache = StoreCache::open(dir.clone()).await.unwrap();
+        let urls = add_pages(&cache, &["A", "B", "C"]).await;
+        add_page(&cache, "A", b"A again").await;
+        let removed = cache.remove(&[key(&urls[1])]).await.unwrap();
+        assert_eq!(removed.entries, 1);
+        assert!(removed.bytes > 0);
+        drop(cache);
+
+        // half a record written when the process died
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(StoreCache::STORE_FILE))
+            .await
+            .unwrap();
+        file.write_all(&[200, 0, 0, 0, b'{']).await.unwrap();
+        drop(file);
+
+        let cache = StoreCache::open(dir.clone()).await.unwrap();
+        assert_eq!(cache.entries().len(), 2);
+        let page = cache.get(&key(&urls[0])).await.unwrap().unwrap();
+        assert_eq!(page.content, b"A again");
+        assert!(cache.get(&key(&urls[1])).await.unwrap().is_none());
+        assert_eq!(
+            cache.get(&key(&urls[2])).await.unwrap().unwrap().content,
+            b"C"
+        );
+        let report = cache.verify(false).await.unwrap();
+        assert_eq!(report.broken_entries, 0);
+
+        // reads are appended on flush and replayed on open
+        let store = dir.join(StoreCache::STORE_FILE);
+        let len = tokio::fs::metadata(&store).await.unwrap().len();
+        cache.flush().await.unwrap();
+        let flushed = tokio::fs::metadata(&store).await.unwrap().len();
+        assert!(flushed > len);
+        cache.flush().await.unwrap();
+        assert_eq!(tokio::fs::metadata(&store).await.unwrap().len(), flushed);
+        drop(cache);
+        let cache = StoreCache::open(dir.clone()).await.unwrap();
+        assert!(cache.entries().iter().all(|e| e.accessed_at > 0));
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_record_is_skipped() {
+        let dir = temp_dir("store-rot");
+        let cache = StoreCache::open(dir.clone()).await.unwrap();
+        let urls = add_pages(&cache, &["A", "B", "C"]).await;
+        drop(cache);
+
+        // the length of B becomes 4 GiB
+        let path = dir.join(StoreCache::STORE_FILE);
+        let mut content = tokio::fs::read(&path).await.unwrap();
+        let b = content.windows(5).rposition(|w| w == b"/B\",\"").unwrap();
+        let b = content[..b]
+            .windows(8)
+            .rposition(|w| w == b"{\"kind\":")
+            .unwrap();
+        content[b - 4..b].copy_from_slice(&u32::MAX.to_le_bytes());
+        tokio::fs::write(&path, &content).await.unwrap();
+
+        let cache = StoreCache::open(dir.clone()).await.unwrap();
+        assert!(cache.get(&key(&urls[1])).await.unwrap().is_none());
+        assert_eq!(
+            cache.get(&key(&urls[2])).await.unwrap().unwrap().content,
+            b"C"
+        );
+        assert_eq!(cache.verify(false).await.unwrap().broken_entries, 1);
+        assert_eq!(
+            tokio::fs::metadata(&path).await.unwrap().len(),
+            content.len() as u64
+        );
+
+        cache.verify(true).await.unwrap();
+        drop(cache);
+        let cache = StoreCache::open(dir.clone()).await.unwrap();
+        assert_eq!(cache.entries().len(), 2);
+        assert_eq!(cache.verify(false).await.unwrap().broken_entries, 0);
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+}
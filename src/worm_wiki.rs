@@ -1,15 +1,19 @@
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::cache;
+use crate::error::ParseError;
 use crate::html;
 use crate::layout::{Layout, LayoutComponent, LayoutParser};
 use crate::parser::Parser;
-use crate::requester::SimpleRequest;
-use crate::spider::RequestFilter;
+use crate::requester::{Page, SimpleRequest};
 use once_cell::sync::Lazy;
-use reqwest::header::HeaderMap;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
 use reqwest::{Method, Url};
 use scraper::{element_ref, ElementRef, Html, Selector};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::warn;
 
 #[derive(Clone)]
 pub struct WormWikiListOfCharacters {
@@ -22,6 +26,47 @@ struct CharacterToDisk {
     base_dir: PathBuf,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Character {
+    name: String,
+    aliases: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CharacterPage {
+    url: Url,
+    characters: Vec<Character>,
+}
+
+impl CharacterToDisk {
+    /// Named by the hash of the url, an encoded wiki url easily exceeds the file name
+    /// limit. The url is stored in the file.
+    fn path(&self, url: &Url) -> PathBuf {
+        self.base_dir.join(format!("{}.json", cache::key(url)))
+    }
+
+    async fn write(&self, url: &Url, characters: Vec<Character>) -> std::io::Result<()> {
+        let mut unique: Vec<Character> = Vec::new();
+        for c in characters {
+            if !unique.contains(&c) {
+                unique.push(c);
+            }
+        }
+        let page = CharacterPage {
+            url: url.clone(),
+            characters: unique,
+        };
+        let path = self.path(url);
+        // re-crawls produce the same file, only touch it if the content changed
+        if let Ok(existing) = fs::read(&path).await {
+            if serde_json::from_slice::<CharacterPage>(&existing).is_ok_and(|e| e == page) {
+                return Ok(());
+            }
+        }
+        cache::write_atomic(&path, &serde_json::to_vec_pretty(&page)?).await
+    }
+}
+
 impl WormWikiListOfCharacters {
     pub fn new(out: PathBuf) -> WormWikiListOfCharacters {
         WormWikiListOfCharacters {
@@ -55,38 +100,52 @@ impl WormWikiListOfCharacters {
     }
 }
 
-pub fn initial() -> Vec<SimpleRequest> {
-    vec![SimpleRequest {
-        method: Method::GET,
-        url: Url::parse("https://worm.fandom.com/wiki/Worm_Wiki").unwrap(),
-        headers: HeaderMap::new(),
-        body: None,
-    }]
-}
-
-pub struct WormRequestFilter;
-
-impl RequestFilter for WormRequestFilter {
-    fn is_valid(&self, request: &SimpleRequest) -> bool {
-        let u = request.url.as_str();
-        u.starts_with("https://worm.fandom.com/wiki")
-    }
-}
+pub const SEED: &str = "https://worm.fandom.com/wiki/Worm_Wiki";
+pub const PREFIX: &str = "https://worm.fandom.com/wiki";
 
 impl Parser for WormWikiListOfCharacters {
-    async fn parse(self, request: &SimpleRequest, page: &str) -> Vec<SimpleRequest> {
+    async fn parse(
+        self,
+        request: &SimpleRequest,
+        page: &Page,
+    ) -> Result<Vec<SimpleRequest>, ParseError> {
+        // cached pages carry no headers, everything we cached was html
+        let is_html = page
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|c| c.to_str().ok())
+            .map_or(true, |c| c.contains("html"));
+        if !is_html {
+            return Ok(Vec::new());
+        }
         (self.layout_parser)
             .clone()
-            .parse(request, page, html::parse, async move |e| {
-                (self).router(e).await
+            .parse(request, &page.body, html::parse, async move |e| {
+                (self).router(request, e).await
             })
             .await
     }
+
+    /// Articles, which is where the characters are, before categories, which link to
+    /// them, before everything else.
+    fn priority(&self, request: &SimpleRequest) -> i64 {
+        let title = request.url.path().trim_start_matches("/wiki/");
+        match title.split_once(':') {
+            None => 2,
+            Some(("Category", _)) => 1,
+            Some(_) => 0,
+        }
+    }
 }
 
 impl WormWikiListOfCharacters {
-    async fn router(&self, extractions: Vec<Extractions>) -> Vec<SimpleRequest> {
-        extractions
+    async fn router(
+        &self,
+        request: &SimpleRequest,
+        extractions: Vec<Extractions>,
+    ) -> Vec<SimpleRequest> {
+        let mut characters = Vec::new();
+        let requests = extractions
             .into_iter()
             .flat_map(|e| match e {
                 Extractions::URL(u) => Some(SimpleRequest {
@@ -94,20 +153,28 @@ impl WormWikiListOfCharacters {
                     url: u,
                     headers: HeaderMap::new(),
                     body: None,
+                    depth: request.depth + 1,
+                    referrer: Some(request.url.clone()),
                 }),
-                Extractions::Character(names) => {
-                    dbg!(names);
+                Extractions::Character(character) => {
+                    characters.push(character);
                     None
                 }
             })
-            .collect()
+            .collect();
+        if !characters.is_empty() {
+            if let Err(e) = self.cahracter_to_disk.write(&request.url, characters).await {
+                warn!(request = ?request, error = ?e, "failed to write characters");
+            }
+        }
+        requests
     }
 }
 
 #[derive(Clone, Debug)]
 enum Extractions {
     URL(Url),
-    Character(Vec<String>),
+    Character(Character),
 }
 
 #[derive(Debug)]
@@ -125,25 +192,20 @@ impl LayoutComponent<Html, Extractions> for ArticleLinksComponent {
             .into_iter()
             .flat_map(|s| s.value().attr("href"))
             .flat_map(|u| {
+                // malformed hrefs are skipped, they must not cost the rest of the page
                 if u.starts_with("/") {
-                    Some(request.url.join(u).unwrap())
+                    request.url.join(u).ok()
                 } else if u.starts_with("http") {
-                    Some(Url::parse(u).unwrap())
+                    Url::parse(u).ok()
                 } else {
                     None
                 }
             })
-            .map(drop_get_parameters)
             .map(Extractions::URL)
             .collect()
     }
 }
 
-fn drop_get_parameters(mut url: Url) -> Url {
-    url.set_query(None);
-    url
-}
-
 #[derive(Debug)]
 struct CharacterSheetComponent;
 impl CharacterSheetComponent {
@@ -156,19 +218,25 @@ impl CharacterSheetComponent {
         let main_name = get_text(
             section
                 .select(&CharacterSheetComponent::MAIN_NAME_SELECTOR)
-                .next()
-                .unwrap(),
+                .next()?,
         )
-        .pop()
-        .unwrap();
+        .pop()?;
         let mut names = vec![main_name];
         let aliases = section
             .select(&CharacterSheetComponent::ALIAS_SELECTOR)
             .map(|s| get_text(s))
-            .next()?;
+            .next()
+            .unwrap_or_default();
         names.extend_from_slice(&aliases);
-        let names = clean_names(names);
-        Some(Extractions::Character(names))
+        let mut names = clean_names(names).into_iter();
+        let name = names.next()?;
+        let mut aliases: Vec<String> = Vec::new();
+        for alias in names {
+            if alias != name && !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+        Some(Extractions::Character(Character { name, aliases }))
     }
 }
 
@@ -302,7 +370,33 @@ impl LayoutComponent<Html, Extractions> for CategoryPage {
 
 #[cfg(test)]
 mod test {
-    use super::clean_names;
+    use reqwest::{header::HeaderMap, Method, Url};
+    use scraper::Html;
+
+    use super::{clean_names, ArticleLinksComponent, CharacterSheetComponent, Extractions};
+    use crate::{layout::LayoutComponent, requester::SimpleRequest};
+
+    #[test]
+    fn test_extract_malformed_page() {
+        let request = SimpleRequest {
+            method: Method::GET,
+            url: Url::parse("https://worm.fandom.com/wiki/Skitter").unwrap(),
+            headers: HeaderMap::new(),
+            body: None,
+            depth: 0,
+            referrer: None,
+        };
+        let html = Html::parse_document(
+            r#"<a href="http://">x</a><a href="/wiki/Weaver">y</a>
+            <aside class="portable-infobox"><div data-source="alias">Bug</div></aside>"#,
+        );
+        let links = ArticleLinksComponent.extract(&request, &html);
+        assert!(matches!(
+            links.as_slice(),
+            [Extractions::URL(u)] if u.as_str() == "https://worm.fandom.com/wiki/Weaver"
+        ));
+        assert!(CharacterSheetComponent.extract(&request, &html).is_empty());
+    }
 
     #[test]
     fn test_clean_names() {